@@ -1,7 +1,14 @@
+use crate::broker::{
+    BrokerOrder, BrokerPosition, OpenOrder, OrderRequest, OrderSide, OrderStatus, OrderType,
+    SubmittedOrder, TimeInForce,
+};
 use anyhow::Error;
 use apca::api::v2::order;
 use apca::api::v2::order::OrderReqInit;
 use apca::api::v2::order::Side::{Buy, Sell};
+use apca::api::v2::orders;
+use apca::api::v2::position;
+use apca::api::v2::positions;
 use apca::data::v2::stream::{drive, Data, MarketData, RealtimeData, IEX};
 use apca::{ApiInfo, Client};
 use crossbeam_channel::{unbounded, Receiver, Sender};
@@ -10,6 +17,7 @@ use num_decimal::Num;
 use std::sync::{Arc, RwLock};
 use std::thread::spawn;
 use tracing::{error, info};
+use uuid::Uuid;
 
 pub fn alpaca_api_thread(
     api_info: ApiInfo,
@@ -32,6 +40,7 @@ pub fn alpaca_api_thread(
             .block_on(async move {
                 info!("Alpaca order processing thread started! Listening for commands");
                 let alpaca_client = Client::new(api_info.clone());
+                let order_api_info = api_info.clone();
                 let rx_req: Receiver<(APIThreadReq, Sender<APIThreadRes>)> = rx_req;
                 let assets: Arc<RwLock<f64>> = assets;
 
@@ -77,72 +86,109 @@ pub fn alpaca_api_thread(
                 *assets.write().unwrap() = acct_data.cash.to_f64().unwrap();
                 drop(acct_data);
 
-                for rx in rx_req.iter() {
-                    //Create order
-                    let req_init: OrderReqInit = OrderReqInit {
-                        type_: order::Type::Market,
-                        ..Default::default()
-                    };
+                for (req, res_tx) in rx_req.iter() {
+                    let res = match req {
+                        APIThreadReq::SubmitOrder { order } => {
+                            let res = submit_order(&alpaca_client, order).await;
 
-                    //make order buy or sell
-                    let req = match rx.0 {
-                        APIThreadReq::ApiBuyStock { symbol, quantity } => {
-                            info!(
-                                "Processing API buy call for symbol: {} of quantity: {}",
-                                &symbol, quantity
-                            );
-                            req_init.init(symbol, Buy, order::Amount::quantity(quantity))
+                            //Instead of roughly calculating the money simply pull the data from the broker directly and update the amount of fiat assets we have
+                            if let Err(e) = refresh_cash(&alpaca_client, &assets).await {
+                                error!("API Error could not update asset_data: {:#?}", e);
+                            }
+                            res
                         }
-                        APIThreadReq::ApiSellStock { symbol, quantity } => {
-                            info!(
-                                "Processing API sell call for symbol: {} of quantity: {}",
-                                &symbol, quantity
-                            );
-                            req_init.init(symbol, Sell, order::Amount::quantity(quantity))
+                        APIThreadReq::CancelOrder { order_id } => {
+                            info!("Processing API cancel call for order: {}", &order_id);
+                            match Uuid::parse_str(&order_id) {
+                                Ok(uuid) => alpaca_client
+                                    .issue::<order::Delete>(&order::Id(uuid))
+                                    .await
+                                    .map(|_| APIThreadRes::Processed)
+                                    .unwrap_or_else(|e| APIThreadRes::Error { error: e.into() }),
+                                Err(e) => APIThreadRes::Error { error: e.into() },
+                            }
+                        }
+                        APIThreadReq::GetOrder { order_id } => {
+                            match get_order(&alpaca_client, &order_api_info, &order_id).await {
+                                Ok(order) => APIThreadRes::Order { order },
+                                Err(error) => APIThreadRes::Error { error },
+                            }
                         }
-                        APIThreadReq::ApiBuyCrypto { symbol, quantity } => {
+                        APIThreadReq::GetPositions => {
+                            match alpaca_client.issue::<positions::Get>(&()).await {
+                                Ok(positions) => APIThreadRes::Positions {
+                                    positions: positions
+                                        .into_iter()
+                                        .map(|position| BrokerPosition {
+                                            symbol: position.symbol,
+                                            //Alpaca gives the size without a sign and the side separately
+                                            quantity: match position.side {
+                                                position::Side::Long => 1.0,
+                                                position::Side::Short => -1.0,
+                                            } * position.quantity.to_f64().unwrap_or_default(),
+                                            average_entry_price: position
+                                                .average_entry_price
+                                                .to_f64()
+                                                .unwrap_or_default(),
+                                        })
+                                        .collect(),
+                                },
+                                Err(e) => APIThreadRes::Error { error: e.into() },
+                            }
+                        }
+                        APIThreadReq::GetOpenOrders => {
+                            //Not nested so bracket legs come back as orders of their own
+                            let req = orders::OrdersReq {
+                                status: orders::Status::Open,
+                                limit: Some(500),
+                                nested: false,
+                                ..Default::default()
+                            };
+                            match alpaca_client.issue::<orders::Get>(&req).await {
+                                Ok(orders) => APIThreadRes::OpenOrders {
+                                    orders: orders
+                                        .into_iter()
+                                        .map(|order| OpenOrder {
+                                            id: order.id.0.to_string(),
+                                            symbol: order.symbol,
+                                            side: match order.side {
+                                                Buy => OrderSide::Buy,
+                                                Sell => OrderSide::Sell,
+                                            },
+                                        })
+                                        .collect(),
+                                },
+                                Err(e) => APIThreadRes::Error { error: e.into() },
+                            }
+                        }
+                        APIThreadReq::GetCash => match refresh_cash(&alpaca_client, &assets).await {
+                            Ok(cash) => APIThreadRes::Cash { cash },
+                            Err(error) => APIThreadRes::Error { error },
+                        },
+                        APIThreadReq::GetEquity => match account_equity(&alpaca_client).await {
+                            Ok(equity) => APIThreadRes::Equity { equity },
+                            Err(error) => APIThreadRes::Error { error },
+                        },
+                        APIThreadReq::BuyCrypto { symbol, quantity } => {
                             info!(
                                 "Processing Crypto API buy call for symbol: {} of fraction value: {}",
                                 &symbol, quantity
                             );
-                            req_init.init(symbol, Buy, order::Amount::quantity(quantity))
+                            let req = OrderReqInit::default().init(symbol, Buy, order::Amount::quantity(quantity));
+                            issue_order(&alpaca_client, &req).await
                         }
-                        APIThreadReq::ApiSellCrypto { symbol, quantity } => {
+                        APIThreadReq::SellCrypto { symbol, quantity } => {
                             info!(
                                 "Processing Crypto API sell call for symbol: {} of fraction value: {}",
                                 &symbol, quantity
                             );
-                            req_init.init(symbol, Sell, order::Amount::quantity(quantity))
+                            let req = OrderReqInit::default().init(symbol, Sell, order::Amount::quantity(quantity));
+                            issue_order(&alpaca_client, &req).await
                         }
                     };
 
-                    //Return result
-                    match alpaca_client.issue::<order::Post>(&req).await {
-                        Ok(_) => {
-                            rx.1.send(APIThreadRes::ApiProcessed).unwrap();
-                            info!("Processesed API call");
-                        }
-                        Err(e) => {
-                            error!("API Error: {:#?}", e);
-                            rx.1.send(APIThreadRes::ApiError { error: e.into() })
-                                .unwrap();
-                        }
-                    }
-
-                    //Instead of roughly calculating the money simply pull the data from the broker directly and update the amount of fiat assets we have
-                    match alpaca_client
-                        .issue::<apca::api::v2::account::Get>(&())
-                        .await
-                    {
-                        Ok(acct_data) => {
-                            if let Some(cash) = acct_data.cash.to_f64() {
-                                *assets.write().unwrap() = cash;
-                            }
-                        }
-                        Err(e) => {
-                            error!("API Error could not update asset_data: {:#?}", e);
-                        }
-                    }
+                    //Return result, if the monitor stopped waiting there is nobody to tell
+                    let _ = res_tx.send(res);
                 }
                 info!("All senders dropped! Exiting API thread!")
             })
@@ -150,14 +196,247 @@ pub fn alpaca_api_thread(
     (tx_req, rx_data)
 }
 
+async fn submit_order(alpaca_client: &Client, order: OrderRequest) -> APIThreadRes {
+    let side = match order.side {
+        OrderSide::Buy => Buy,
+        OrderSide::Sell => Sell,
+    };
+    info!(
+        "Processing API {:?} {:?} call for symbol: {} of quantity: {}",
+        side, order.order_type, &order.symbol, order.quantity
+    );
+
+    //A bracket with both exits is a bracket order, with one it's one-triggers-other
+    let (class, take_profit, stop_loss) = match order.bracket {
+        Some(bracket) => (
+            if bracket.take_profit.is_some() && bracket.stop_loss.is_some() {
+                order::Class::Bracket
+            } else {
+                order::Class::OneTriggersOther
+            },
+            bracket
+                .take_profit
+                .map(|price| order::TakeProfit::Limit(to_price(price))),
+            bracket
+                .stop_loss
+                .map(|price| order::StopLoss::Stop(to_price(price))),
+        ),
+        None => (order::Class::Simple, None, None),
+    };
+    let (type_, limit_price, stop_price) = match order.order_type {
+        OrderType::Market => (order::Type::Market, None, None),
+        OrderType::Limit { limit_price } => (order::Type::Limit, Some(limit_price), None),
+        OrderType::Stop { stop_price } => (order::Type::Stop, None, Some(stop_price)),
+        OrderType::StopLimit {
+            stop_price,
+            limit_price,
+        } => (order::Type::StopLimit, Some(limit_price), Some(stop_price)),
+    };
+    //apca has no immediate or cancel, those go in as day orders and whatever didn't fill right away gets canceled below
+    let time_in_force = match order.time_in_force {
+        TimeInForce::Day | TimeInForce::Ioc => order::TimeInForce::Day,
+        TimeInForce::Gtc => order::TimeInForce::UntilCanceled,
+        TimeInForce::Opg => order::TimeInForce::UntilMarketOpen,
+        TimeInForce::Cls => order::TimeInForce::UntilMarketClose,
+    };
+
+    //Create order
+    let req = OrderReqInit {
+        class,
+        type_,
+        time_in_force,
+        limit_price: limit_price.map(to_price),
+        stop_price: stop_price.map(to_price),
+        take_profit,
+        stop_loss,
+        extended_hours: order.extended_hours,
+        ..Default::default()
+    }
+    .init(
+        order.symbol,
+        side,
+        order::Amount::quantity(to_num(order.quantity)),
+    );
+
+    let res = issue_order(alpaca_client, &req).await;
+    if let (TimeInForce::Ioc, APIThreadRes::OrderSubmitted { order }) = (order.time_in_force, &res)
+    {
+        cancel_rest(alpaca_client, &order.id).await;
+    }
+    res
+}
+
+//Cancels what's left of an order, it's fine if it already filled
+async fn cancel_rest(alpaca_client: &Client, order_id: &str) {
+    let Ok(uuid) = Uuid::parse_str(order_id) else {
+        return;
+    };
+    if let Err(e) = alpaca_client.issue::<order::Delete>(&order::Id(uuid)).await {
+        info!("Nothing left to cancel of order {}: {}", order_id, e);
+    }
+}
+
+async fn issue_order(alpaca_client: &Client, req: &order::OrderReq) -> APIThreadRes {
+    match alpaca_client.issue::<order::Post>(req).await {
+        Ok(order) => {
+            info!("Processesed API call");
+            APIThreadRes::OrderSubmitted {
+                order: SubmittedOrder {
+                    id: order.id.0.to_string(),
+                    legs: order.legs.iter().map(|leg| leg.id.0.to_string()).collect(),
+                },
+            }
+        }
+        Err(e) => {
+            error!("API Error: {:#?}", e);
+            APIThreadRes::Error { error: e.into() }
+        }
+    }
+}
+
+//Fractional quantities are sent with up to 9 decimals, the most alpaca takes
+fn to_num(quantity: f64) -> Num {
+    const SCALE: i64 = 1_000_000_000;
+    Num::new((quantity * SCALE as f64).round() as i64, SCALE)
+}
+
+//Alpaca rejects sub penny prices on stocks over a dollar
+fn to_price(price: f64) -> Num {
+    Num::new((price * 100.0).round() as i64, 100)
+}
+
+//Gets the order, or the one that replaced it when it was changed (by hand, the bot never replaces orders)
+//The returned ID is the replacing order's so the monitor follows that one from now on
+async fn get_order(
+    alpaca_client: &Client,
+    api_info: &ApiInfo,
+    order_id: &str,
+) -> Result<BrokerOrder, Error> {
+    let mut uuid = Uuid::parse_str(order_id)?;
+    //Shares the replaced orders filled before they were replaced, the replacing order only covers the rest
+    let mut filled_before = 0.0;
+    let mut filled_value_before = 0.0;
+    loop {
+        let order = alpaca_client.issue::<order::Get>(&order::Id(uuid)).await?;
+        let mut broker_order = to_broker_order(&order);
+        if order.status != order::Status::Replaced {
+            if filled_before > 0.0 {
+                let filled_value = broker_order.filled_avg_price.unwrap_or_default()
+                    * broker_order.filled_quantity;
+                broker_order.filled_quantity += filled_before;
+                broker_order.filled_avg_price =
+                    Some((filled_value + filled_value_before) / broker_order.filled_quantity);
+            }
+            return Ok(broker_order);
+        }
+        filled_before += broker_order.filled_quantity;
+        filled_value_before +=
+            broker_order.filled_avg_price.unwrap_or_default() * broker_order.filled_quantity;
+        uuid = Uuid::parse_str(&replaced_by(api_info, &broker_order.id).await?)?;
+    }
+}
+
+//Same key headers apca sends, for the few calls it doesn't cover
+fn authorized(api_info: &ApiInfo, request: ureq::Request) -> ureq::Request {
+    request
+        .set("APCA-API-KEY-ID", &api_info.key_id)
+        .set("APCA-API-SECRET-KEY", &api_info.secret)
+}
+
+//apca's order doesn't have replaced_by, so this one is asked for straight from the API
+//ureq blocks, so it runs on tokio's blocking threads instead of holding up the API thread
+async fn replaced_by(api_info: &ApiInfo, order_id: &str) -> Result<String, Error> {
+    let url = api_info
+        .api_base_url
+        .join(&format!("v2/orders/{}", order_id))?;
+    let request = authorized(api_info, ureq::get(url.as_str()));
+    let order: serde_json::Value =
+        tokio::task::spawn_blocking(move || -> Result<serde_json::Value, Error> {
+            Ok(request.call()?.into_json()?)
+        })
+        .await??;
+    order["replaced_by"]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| Error::msg(format!("Order {} was replaced by nothing", order_id)))
+}
+
+fn to_broker_order(order: &order::Order) -> BrokerOrder {
+    use order::Status;
+    BrokerOrder {
+        id: order.id.0.to_string(),
+        status: match order.status {
+            Status::Filled => OrderStatus::Filled,
+            Status::PartiallyFilled => OrderStatus::PartiallyFilled,
+            Status::Rejected => OrderStatus::Rejected,
+            Status::Canceled | Status::Expired => OrderStatus::Canceled,
+            //Done for day orders pick up again next session, replaced ones are followed by get_order
+            _ => OrderStatus::Open,
+        },
+        filled_quantity: order.filled_quantity.to_f64().unwrap_or_default(),
+        filled_avg_price: order
+            .average_fill_price
+            .as_ref()
+            .and_then(|price| price.to_f64()),
+    }
+}
+
+async fn account_equity(alpaca_client: &Client) -> Result<f64, Error> {
+    alpaca_client
+        .issue::<apca::api::v2::account::Get>(&())
+        .await?
+        .equity
+        .to_f64()
+        .ok_or_else(|| Error::msg("Could not convert equity to f64"))
+}
+
+//Pulls the current cash from the alpaca account and stores it as the allocated currency
+async fn refresh_cash(alpaca_client: &Client, assets: &Arc<RwLock<f64>>) -> Result<f64, Error> {
+    let acct_data = alpaca_client
+        .issue::<apca::api::v2::account::Get>(&())
+        .await?;
+    let cash = acct_data
+        .cash
+        .to_f64()
+        .ok_or_else(|| Error::msg("Could not convert cash to f64"))?;
+    *assets.write().unwrap() = cash;
+    Ok(cash)
+}
+
 pub enum APIThreadReq {
-    ApiBuyStock { symbol: String, quantity: usize },
-    ApiSellStock { symbol: String, quantity: usize },
-    ApiBuyCrypto { symbol: String, quantity: Num },
-    ApiSellCrypto { symbol: String, quantity: Num },
+    SubmitOrder {
+        order: OrderRequest,
+    },
+    CancelOrder {
+        order_id: String,
+    },
+    GetOrder {
+        order_id: String,
+    },
+    GetPositions,
+    GetOpenOrders,
+    GetCash,
+    GetEquity,
+    //Only used by the disabled crypto path
+    #[allow(dead_code)]
+    BuyCrypto {
+        symbol: String,
+        quantity: Num,
+    },
+    #[allow(dead_code)]
+    SellCrypto {
+        symbol: String,
+        quantity: Num,
+    },
 }
 
 pub enum APIThreadRes {
-    ApiProcessed,
-    ApiError { error: Error },
+    Processed,
+    OrderSubmitted { order: SubmittedOrder },
+    Order { order: BrokerOrder },
+    Positions { positions: Vec<BrokerPosition> },
+    OpenOrders { orders: Vec<OpenOrder> },
+    Cash { cash: f64 },
+    Equity { equity: f64 },
+    Error { error: Error },
 }
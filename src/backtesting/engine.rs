@@ -0,0 +1,169 @@
+use crate::backtesting::report::EquityPoint;
+use crate::broker::{Broker, SimulatedBroker};
+use crate::StockMonitor;
+use anyhow::{Error, Result};
+use apca::data::v2::stream::Bar;
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use num_decimal::Num;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use tracing::{error, info, warn};
+
+//One row of a file in backtest_data, prices are kept as text so they convert to Num without losing precision
+#[derive(Deserialize)]
+struct CsvBar {
+    datetime: String,
+    open: String,
+    high: String,
+    low: String,
+    close: String,
+    volume: u64,
+}
+
+///Reads ./backtest_data/{symbol}.csv into the same bars the alpaca market data stream sends
+///The CSV time stamps are in exchange time, utc_offset_hours is how far that is from UTC (-4 for New York in summer)
+pub fn load_bars(symbol: &str, utc_offset_hours: i32) -> Result<Vec<Bar>> {
+    let mut reader = csv::Reader::from_path(format!("./backtest_data/{}.csv", symbol))?;
+    let mut bars = vec![];
+
+    for record in reader.deserialize() {
+        let row: CsvBar = record?;
+        let price = |text: &str| {
+            Num::from_str(text).map_err(|e| {
+                Error::msg(format!(
+                    "[{}] Bad price {} at {}: {}",
+                    symbol, text, &row.datetime, e
+                ))
+            })
+        };
+        let local_time = NaiveDateTime::parse_from_str(&row.datetime, "%Y-%m-%d %H:%M:%S")?;
+
+        bars.push(Bar {
+            symbol: symbol.to_string(),
+            open_price: price(&row.open)?,
+            high_price: price(&row.high)?,
+            low_price: price(&row.low)?,
+            close_price: price(&row.close)?,
+            volume: row.volume,
+            timestamp: (local_time - Duration::hours(utc_offset_hours as i64)).and_utc(),
+        });
+    }
+
+    Ok(bars)
+}
+
+//A buy that got fewer shares than its intensity while other symbols had cash tied up in positions
+pub struct CashContention {
+    pub time: DateTime<Utc>,
+    pub symbol: String,
+    pub shares_short: f64,
+    pub held_by: Vec<String>,
+}
+
+pub struct BacktestResult {
+    pub contentions: Vec<CashContention>,
+    //Cash plus the value of every open position, one point per time stamp
+    pub equity_curve: Vec<EquityPoint>,
+    //Starting cash plus the profit of a single symbol, one point per bar of that symbol
+    pub symbol_curves: BTreeMap<String, Vec<EquityPoint>>,
+}
+
+///Loads the bars of every symbol and merges them by time stamp
+///Bars with the same time stamp are put in symbol order, so the shared cash in the broker is spent the same way on every run
+pub fn load_portfolio_bars(symbols: &[String], utc_offset_hours: i32) -> Result<Vec<Bar>> {
+    let mut bars = vec![];
+    for symbol in symbols {
+        bars.append(&mut load_bars(symbol, utc_offset_hours)?);
+    }
+    //Stable sort keeps each file's own order for bars with equal time stamps
+    bars.sort_by(|a, b| {
+        a.timestamp
+            .cmp(&b.timestamp)
+            .then_with(|| a.symbol.cmp(&b.symbol))
+    });
+    Ok(bars)
+}
+
+///Feeds the bars through StockMonitor::run one at a time, exactly like the live ticker loop does
+pub fn run_portfolio(
+    monitors: &mut BTreeMap<String, &mut StockMonitor>,
+    broker: &SimulatedBroker,
+    bars: &[Bar],
+) -> Result<BacktestResult> {
+    let starting_cash = broker.cash()?;
+    info!(
+        "Replaying {} bars across {} symbols",
+        bars.len(),
+        monitors.len()
+    );
+
+    let mut result = BacktestResult {
+        contentions: vec![],
+        equity_curve: vec![],
+        symbol_curves: monitors
+            .keys()
+            .map(|symbol| (symbol.clone(), vec![]))
+            .collect(),
+    };
+    for bar in bars {
+        let monitor = monitors.get_mut(&bar.symbol).unwrap();
+        let time = bar.timestamp;
+        broker.mark(bar);
+
+        if let Err(e) = monitor.run(bar.clone()) {
+            error!("[{}] Error: {:#?}", &monitor.symbol, e);
+        }
+
+        result
+            .symbol_curves
+            .get_mut(&monitor.symbol)
+            .unwrap()
+            .push(EquityPoint {
+                time,
+                equity: starting_cash
+                    + broker.realized_pnl(&monitor.symbol)
+                    + broker.unrealized_pnl(&monitor.symbol),
+                invested: broker.market_value(&monitor.symbol),
+            });
+
+        let invested: f64 = broker
+            .positions()?
+            .iter()
+            .map(|position| broker.market_value(&position.symbol))
+            .fold(0.0, |total, value| total + value);
+        let point = EquityPoint {
+            time,
+            equity: broker.cash()? + invested,
+            invested,
+        };
+        //Only keep the last point for bars that share a time stamp
+        match result.equity_curve.last_mut() {
+            Some(last) if last.time == time => *last = point,
+            _ => result.equity_curve.push(point),
+        }
+
+        if let Some(shares_short) = monitor.take_cash_shortfall() {
+            let held_by: Vec<String> = broker
+                .positions()?
+                .into_iter()
+                .map(|position| position.symbol)
+                .filter(|symbol| symbol != &monitor.symbol)
+                .collect();
+            if !held_by.is_empty() {
+                warn!(
+                    "[{}] Short {} shares on a buy while {:?} held cash",
+                    &monitor.symbol, shares_short, &held_by
+                );
+                result.contentions.push(CashContention {
+                    time,
+                    symbol: monitor.symbol.clone(),
+                    shares_short,
+                    held_by,
+                });
+            }
+        }
+    }
+
+    Ok(result)
+}
@@ -0,0 +1,243 @@
+use crate::broker::OrderSide;
+use serde::Deserialize;
+
+///How the simulated broker prices its fills, set under [stock_engine_config.fill_model] in the config
+#[derive(Deserialize, Clone, Default)]
+pub struct FillModel {
+    #[serde(default)]
+    pub commission: Commission,
+    #[serde(default)]
+    pub slippage: Slippage,
+    #[serde(default)]
+    pub fill_at: FillTiming,
+    //Bid/ask spread in basis points, buys pay the ask and sells get the bid so every fill gives up half of it
+    //The bars only have trade prices so it's one number for every symbol and bar, on top of the slippage
+    #[serde(default)]
+    pub spread_bps: f64,
+    //Yearly percent of a short position's value charged for borrowing the shares, for as long as it's held
+    #[serde(default)]
+    pub borrow_rate: f64,
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Commission {
+    #[default]
+    None,
+    //Flat dollar amount per order
+    Fixed {
+        per_order: f64,
+    },
+    //Percent of the order's dollar value
+    Percent {
+        percent: f64,
+    },
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Slippage {
+    #[default]
+    None,
+    //Every fill is this many basis points worse than the bar price
+    FixedBps {
+        bps: f64,
+    },
+    //Basis points of slippage for every percent of the bar's volume the order takes up
+    VolumeParticipation {
+        bps_per_percent: f64,
+    },
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FillTiming {
+    //Fill at the close of the bar the monitor decided on
+    #[default]
+    CurrentClose,
+    //Wait for the next bar of the symbol and fill at its open
+    NextOpen,
+}
+
+impl FillModel {
+    //Moves the bar price against the order by half the spread and the slippage model
+    pub fn fill_price(&self, side: OrderSide, price: f64, quantity: f64, bar_volume: f64) -> f64 {
+        let slippage = match self.slippage {
+            Slippage::None => 0.0,
+            Slippage::FixedBps { bps } => bps,
+            Slippage::VolumeParticipation { bps_per_percent } => {
+                //A bar with no volume means the order is all of it
+                let participation = if bar_volume > 0.0 {
+                    (quantity / bar_volume * 100.0).min(100.0)
+                } else {
+                    100.0
+                };
+                bps_per_percent * participation
+            }
+        };
+        let bps = self.spread_bps / 2.0 + slippage;
+
+        match side {
+            OrderSide::Buy => price * (1.0 + bps / 10_000.0),
+            OrderSide::Sell => price * (1.0 - bps / 10_000.0),
+        }
+    }
+
+    pub fn commission(&self, order_value: f64) -> f64 {
+        match self.commission {
+            Commission::None => 0.0,
+            Commission::Fixed { per_order } => per_order,
+            Commission::Percent { percent } => order_value * percent / 100.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::broker::{
+        Broker, OrderRequest, OrderStatus, OrderType, SimulatedBroker, TimeInForce,
+    };
+    use apca::data::v2::stream::Bar;
+    use chrono::{TimeZone, Utc};
+    use num_decimal::Num;
+    use std::sync::{Arc, RwLock};
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    fn model(slippage: Slippage, spread_bps: f64) -> FillModel {
+        FillModel {
+            slippage,
+            spread_bps,
+            ..FillModel::default()
+        }
+    }
+
+    fn bar(minute: u32, open: f64, close: f64) -> Bar {
+        let price = |price: f64| Num::new((price * 100.0) as i64, 100);
+        Bar {
+            symbol: "AAPL".to_string(),
+            open_price: price(open),
+            high_price: price(open.max(close)),
+            low_price: price(open.min(close)),
+            close_price: price(close),
+            volume: 1000,
+            timestamp: Utc.with_ymd_and_hms(2022, 6, 1, 15, minute, 0).unwrap(),
+        }
+    }
+
+    fn market_buy() -> OrderRequest {
+        OrderRequest {
+            symbol: "AAPL".to_string(),
+            side: OrderSide::Buy,
+            quantity: 1.0,
+            reference_price: 100.0,
+            order_type: OrderType::Market,
+            time_in_force: TimeInForce::Day,
+            extended_hours: false,
+            bracket: None,
+        }
+    }
+
+    #[test]
+    fn commissions() {
+        assert_eq!(FillModel::default().commission(1000.0), 0.0);
+        let fixed = FillModel {
+            commission: Commission::Fixed { per_order: 1.5 },
+            ..FillModel::default()
+        };
+        assert_eq!(fixed.commission(1000.0), 1.5);
+        assert_eq!(fixed.commission(10.0), 1.5);
+        let percent = FillModel {
+            commission: Commission::Percent { percent: 0.1 },
+            ..FillModel::default()
+        };
+        assert!(close(percent.commission(1000.0), 1.0));
+    }
+
+    #[test]
+    fn fixed_bps_slippage_goes_against_the_order() {
+        let model = model(Slippage::FixedBps { bps: 10.0 }, 0.0);
+        assert!(close(
+            model.fill_price(OrderSide::Buy, 100.0, 1.0, 1000.0),
+            100.1
+        ));
+        assert!(close(
+            model.fill_price(OrderSide::Sell, 100.0, 1.0, 1000.0),
+            99.9
+        ));
+        assert_eq!(
+            FillModel::default().fill_price(OrderSide::Buy, 100.0, 1.0, 1000.0),
+            100.0
+        );
+    }
+
+    #[test]
+    fn volume_participation_slippage_grows_with_the_order() {
+        let model = model(
+            Slippage::VolumeParticipation {
+                bps_per_percent: 5.0,
+            },
+            0.0,
+        );
+        //1% of the bar's volume
+        assert!(close(
+            model.fill_price(OrderSide::Buy, 100.0, 10.0, 1000.0),
+            100.05
+        ));
+        //5%
+        assert!(close(
+            model.fill_price(OrderSide::Sell, 100.0, 50.0, 1000.0),
+            99.75
+        ));
+        //Capped at all of the volume, same as a bar with none
+        let capped = model.fill_price(OrderSide::Buy, 100.0, 5000.0, 1000.0);
+        assert!(close(capped, 105.0));
+        assert!(close(
+            model.fill_price(OrderSide::Buy, 100.0, 1.0, 0.0),
+            capped
+        ));
+    }
+
+    #[test]
+    fn spread_costs_half_on_each_side_on_top_of_slippage() {
+        let model = model(Slippage::FixedBps { bps: 2.0 }, 10.0);
+        assert!(close(
+            model.fill_price(OrderSide::Buy, 100.0, 1.0, 1000.0),
+            100.07
+        ));
+        assert!(close(
+            model.fill_price(OrderSide::Sell, 100.0, 1.0, 1000.0),
+            99.93
+        ));
+    }
+
+    #[test]
+    fn current_close_fills_right_away() {
+        let broker = SimulatedBroker::new(Arc::new(RwLock::new(1000.0)), FillModel::default());
+        broker.mark(&bar(0, 99.0, 100.0));
+        let order = broker.submit_order(market_buy()).unwrap();
+        let filled = broker.order(&order.id).unwrap();
+        assert_eq!(filled.status, OrderStatus::Filled);
+        assert_eq!(filled.filled_avg_price, Some(100.0));
+    }
+
+    #[test]
+    fn next_open_waits_for_the_next_bar() {
+        let fill_model = FillModel {
+            fill_at: FillTiming::NextOpen,
+            ..FillModel::default()
+        };
+        let broker = SimulatedBroker::new(Arc::new(RwLock::new(1000.0)), fill_model);
+        broker.mark(&bar(0, 99.0, 100.0));
+        let order = broker.submit_order(market_buy()).unwrap();
+        assert_eq!(broker.order(&order.id).unwrap().status, OrderStatus::Open);
+
+        broker.mark(&bar(1, 101.0, 102.0));
+        let filled = broker.order(&order.id).unwrap();
+        assert_eq!(filled.status, OrderStatus::Filled);
+        assert_eq!(filled.filled_avg_price, Some(101.0));
+    }
+}
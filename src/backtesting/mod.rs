@@ -0,0 +1,6 @@
+pub mod engine;
+pub mod fill_model;
+pub mod monte_carlo;
+pub mod optimizer;
+pub mod report;
+pub mod walk_forward;
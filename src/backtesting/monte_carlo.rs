@@ -0,0 +1,365 @@
+use crate::backtesting::report::RoundTrip;
+use anyhow::Result;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::fs::{create_dir_all, File};
+use std::path::Path;
+use tracing::info;
+
+//Percentiles reported for drawdowns and terminal equity
+const PERCENTILES: [f64; 7] = [1.0, 5.0, 25.0, 50.0, 75.0, 95.0, 99.0];
+const HISTOGRAM_BUCKETS: usize = 20;
+
+///Set under [stock_engine_config.monte_carlo] in the config, runs after every backtest when present
+#[derive(Deserialize, Clone)]
+pub struct MonteCarloConfig {
+    #[serde(default = "default_iterations")]
+    pub iterations: usize,
+    #[serde(default)]
+    pub method: Resampling,
+    //Same seed, same trades, same results
+    #[serde(default)]
+    pub seed: u64,
+    //Any run whose equity drops below this many dollars counts as ruined
+    pub equity_floor: f64,
+}
+
+fn default_iterations() -> usize {
+    10_000
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Resampling {
+    //Draws as many trades as the backtest made, with replacement
+    #[default]
+    Bootstrap,
+    //Same trades in a random order, only the path changes so terminal equity is always the same
+    Shuffle,
+}
+
+#[derive(Serialize)]
+pub struct Percentile {
+    pub percentile: f64,
+    pub max_drawdown: f64,
+    pub max_drawdown_pct: f64,
+    pub terminal_equity: f64,
+}
+
+//Terminal equities from low to high
+#[derive(Serialize)]
+pub struct HistogramBucket {
+    pub from: f64,
+    pub to: f64,
+    pub runs: usize,
+}
+
+#[derive(Serialize)]
+pub struct MonteCarloReport {
+    pub method: Resampling,
+    pub iterations: usize,
+    pub seed: u64,
+    pub trades: usize,
+    pub starting_equity: f64,
+    pub equity_floor: f64,
+    //Share of runs that went under the floor at any point, from 0 to 1
+    pub probability_below_floor: f64,
+    pub mean_terminal_equity: f64,
+    pub percentiles: Vec<Percentile>,
+    pub terminal_equity_histogram: Vec<HistogramBucket>,
+}
+
+//What a single resampled run ended up with
+struct Run {
+    max_drawdown: f64,
+    max_drawdown_pct: f64,
+    terminal_equity: f64,
+    below_floor: bool,
+}
+
+fn replay(starting_equity: f64, floor: f64, pnls: impl Iterator<Item = f64>) -> Run {
+    let mut equity = starting_equity;
+    let mut peak = starting_equity;
+    let mut run = Run {
+        max_drawdown: 0.0,
+        max_drawdown_pct: 0.0,
+        terminal_equity: starting_equity,
+        below_floor: starting_equity < floor,
+    };
+    for pnl in pnls {
+        equity += pnl;
+        peak = peak.max(equity);
+        let drawdown = peak - equity;
+        if drawdown > run.max_drawdown {
+            run.max_drawdown = drawdown;
+            run.max_drawdown_pct = drawdown / peak * 100.0;
+        }
+        run.below_floor |= equity < floor;
+    }
+    run.terminal_equity = equity;
+    run
+}
+
+//Nearest rank on an already sorted list
+fn percentile(sorted: &[f64], percentile: f64) -> f64 {
+    let index = ((percentile / 100.0) * (sorted.len() - 1) as f64).round() as usize;
+    sorted[index]
+}
+
+fn histogram(sorted: &[f64]) -> Vec<HistogramBucket> {
+    let (low, high) = (sorted[0], sorted[sorted.len() - 1]);
+    //Every run ended the same, shuffling always does this
+    if high == low {
+        return vec![HistogramBucket {
+            from: low,
+            to: high,
+            runs: sorted.len(),
+        }];
+    }
+
+    let width = (high - low) / HISTOGRAM_BUCKETS as f64;
+    let mut buckets: Vec<HistogramBucket> = (0..HISTOGRAM_BUCKETS)
+        .map(|i| HistogramBucket {
+            from: low + width * i as f64,
+            to: low + width * (i + 1) as f64,
+            runs: 0,
+        })
+        .collect();
+    for &equity in sorted {
+        let index = (((equity - low) / width) as usize).min(HISTOGRAM_BUCKETS - 1);
+        buckets[index].runs += 1;
+    }
+    buckets
+}
+
+///Resamples the trade log to see how much worse (or better) the same trades could have gone, None if there were no trades
+pub fn simulate(
+    trades: &[RoundTrip],
+    starting_equity: f64,
+    config: &MonteCarloConfig,
+) -> Option<MonteCarloReport> {
+    if trades.is_empty() || config.iterations == 0 {
+        return None;
+    }
+
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let mut pnls: Vec<f64> = trades.iter().map(|trade| trade.pnl).collect();
+    let runs: Vec<Run> = (0..config.iterations)
+        .map(|_| match config.method {
+            Resampling::Bootstrap => {
+                let drawn: Vec<f64> = (0..pnls.len())
+                    .map(|_| pnls[rng.gen_range(0..pnls.len())])
+                    .collect();
+                replay(starting_equity, config.equity_floor, drawn.into_iter())
+            }
+            Resampling::Shuffle => {
+                pnls.shuffle(&mut rng);
+                replay(starting_equity, config.equity_floor, pnls.iter().copied())
+            }
+        })
+        .collect();
+
+    let sorted = |value: fn(&Run) -> f64| {
+        let mut values: Vec<f64> = runs.iter().map(value).collect();
+        values.sort_by(f64::total_cmp);
+        values
+    };
+    let drawdowns = sorted(|run| run.max_drawdown);
+    let drawdown_pcts = sorted(|run| run.max_drawdown_pct);
+    let terminal = sorted(|run| run.terminal_equity);
+
+    Some(MonteCarloReport {
+        method: config.method,
+        iterations: config.iterations,
+        seed: config.seed,
+        trades: trades.len(),
+        starting_equity,
+        equity_floor: config.equity_floor,
+        probability_below_floor: runs.iter().filter(|run| run.below_floor).count() as f64
+            / runs.len() as f64,
+        mean_terminal_equity: terminal.iter().fold(0.0, |total, equity| total + equity)
+            / terminal.len() as f64,
+        percentiles: PERCENTILES
+            .iter()
+            .map(|&p| Percentile {
+                percentile: p,
+                max_drawdown: percentile(&drawdowns, p),
+                max_drawdown_pct: percentile(&drawdown_pcts, p),
+                terminal_equity: percentile(&terminal, p),
+            })
+            .collect(),
+        terminal_equity_histogram: histogram(&terminal),
+    })
+}
+
+impl MonteCarloReport {
+    pub fn log(&self) {
+        info!(
+            "Monte Carlo ({:?}, {} runs of {} trades): {:.2}% chance of going under {}, mean ending equity {:.2}",
+            self.method,
+            self.iterations,
+            self.trades,
+            self.probability_below_floor * 100.0,
+            self.equity_floor,
+            self.mean_terminal_equity
+        );
+        for p in &self.percentiles {
+            info!(
+                "Monte Carlo p{}: max drawdown: {:.2} ({:.2}%), ending equity: {:.2}",
+                p.percentile, p.max_drawdown, p.max_drawdown_pct, p.terminal_equity
+            );
+        }
+    }
+
+    ///Writes monte_carlo.json plus the percentiles as CSV into the given directory
+    pub fn write(&self, dir: &str) -> Result<()> {
+        let dir = Path::new(dir);
+        create_dir_all(dir)?;
+
+        serde_json::to_writer_pretty(File::create(dir.join("monte_carlo.json"))?, self)?;
+
+        let mut percentiles = csv::Writer::from_path(dir.join("monte_carlo.csv"))?;
+        for p in &self.percentiles {
+            percentiles.serialize(p)?;
+        }
+        percentiles.flush()?;
+
+        info!("Monte Carlo report written to {}", dir.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    fn trades(pnls: &[f64]) -> Vec<RoundTrip> {
+        let time = Utc.with_ymd_and_hms(2022, 6, 1, 15, 0, 0).unwrap();
+        pnls.iter()
+            .map(|&pnl| RoundTrip {
+                symbol: "AAPL".to_string(),
+                short: false,
+                entry_time: time,
+                exit_time: time,
+                quantity: 1.0,
+                entry_price: 100.0,
+                exit_price: 100.0 + pnl,
+                commission: 0.0,
+                pnl,
+                return_pct: pnl,
+                holding_minutes: 0,
+            })
+            .collect()
+    }
+
+    fn config(method: Resampling, seed: u64) -> MonteCarloConfig {
+        MonteCarloConfig {
+            iterations: 500,
+            method,
+            seed,
+            equity_floor: 950.0,
+        }
+    }
+
+    fn json(report: &MonteCarloReport) -> String {
+        serde_json::to_string(report).unwrap()
+    }
+
+    const PNLS: [f64; 6] = [30.0, -20.0, 15.0, -40.0, 25.0, -5.0];
+
+    #[test]
+    fn same_seed_gives_the_same_report() {
+        for method in [Resampling::Bootstrap, Resampling::Shuffle] {
+            let first = simulate(&trades(&PNLS), 1000.0, &config(method, 7)).unwrap();
+            let second = simulate(&trades(&PNLS), 1000.0, &config(method, 7)).unwrap();
+            assert_eq!(json(&first), json(&second));
+        }
+    }
+
+    #[test]
+    fn different_seeds_give_different_reports() {
+        for method in [Resampling::Bootstrap, Resampling::Shuffle] {
+            let first = simulate(&trades(&PNLS), 1000.0, &config(method, 1)).unwrap();
+            let second = simulate(&trades(&PNLS), 1000.0, &config(method, 2)).unwrap();
+            assert_ne!(json(&first), json(&second));
+        }
+    }
+
+    #[test]
+    fn shuffling_keeps_the_ending_equity() {
+        let report = simulate(&trades(&PNLS), 1000.0, &config(Resampling::Shuffle, 3)).unwrap();
+        for p in &report.percentiles {
+            assert!(close(p.terminal_equity, 1005.0));
+        }
+        assert!(close(report.mean_terminal_equity, 1005.0));
+        assert_eq!(report.terminal_equity_histogram.len(), 1);
+        assert_eq!(report.terminal_equity_histogram[0].runs, 500);
+        //Order still matters for the drawdowns
+        let drawdowns = &report.percentiles;
+        assert!(drawdowns[0].max_drawdown < drawdowns[drawdowns.len() - 1].max_drawdown);
+    }
+
+    #[test]
+    fn bootstrapping_spreads_the_ending_equity() {
+        let report = simulate(&trades(&PNLS), 1000.0, &config(Resampling::Bootstrap, 3)).unwrap();
+        let first = &report.percentiles[0];
+        let last = &report.percentiles[report.percentiles.len() - 1];
+        assert!(first.terminal_equity < 1005.0 && last.terminal_equity > 1005.0);
+        assert_eq!(report.terminal_equity_histogram.len(), HISTOGRAM_BUCKETS);
+        let runs: usize = report
+            .terminal_equity_histogram
+            .iter()
+            .map(|bucket| bucket.runs)
+            .sum();
+        assert_eq!(runs, 500);
+        assert!(report.probability_below_floor > 0.0 && report.probability_below_floor < 1.0);
+    }
+
+    #[test]
+    fn nothing_to_resample() {
+        assert!(simulate(&[], 1000.0, &config(Resampling::Bootstrap, 0)).is_none());
+        let none = MonteCarloConfig {
+            iterations: 0,
+            ..config(Resampling::Bootstrap, 0)
+        };
+        assert!(simulate(&trades(&PNLS), 1000.0, &none).is_none());
+    }
+
+    #[test]
+    fn replay_tracks_the_worst_drawdown_and_the_floor() {
+        let run = replay(1000.0, 950.0, [50.0, -110.0, 20.0, -10.0].into_iter());
+        assert!(close(run.max_drawdown, 110.0));
+        assert!(close(run.max_drawdown_pct, 110.0 / 1050.0 * 100.0));
+        assert!(close(run.terminal_equity, 950.0));
+        assert!(run.below_floor);
+        assert!(!replay(1000.0, 950.0, [-40.0, 10.0].into_iter()).below_floor);
+    }
+
+    #[test]
+    fn percentiles_use_the_nearest_rank() {
+        let sorted: Vec<f64> = (0..=10).map(f64::from).collect();
+        assert_eq!(percentile(&sorted, 0.0), 0.0);
+        assert_eq!(percentile(&sorted, 50.0), 5.0);
+        assert_eq!(percentile(&sorted, 95.0), 10.0);
+        assert_eq!(percentile(&sorted, 99.0), 10.0);
+        assert_eq!(percentile(&sorted, 24.0), 2.0);
+    }
+
+    #[test]
+    fn histogram_puts_the_highest_in_the_last_bucket() {
+        let sorted: Vec<f64> = (0..=20).map(f64::from).collect();
+        let buckets = histogram(&sorted);
+        assert_eq!(buckets.len(), HISTOGRAM_BUCKETS);
+        assert!(close(buckets[0].from, 0.0) && close(buckets[0].to, 1.0));
+        assert_eq!(buckets[0].runs, 1);
+        assert_eq!(buckets[HISTOGRAM_BUCKETS - 1].runs, 2);
+        assert_eq!(buckets.iter().map(|bucket| bucket.runs).sum::<usize>(), 21);
+    }
+}
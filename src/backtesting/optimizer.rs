@@ -0,0 +1,451 @@
+use crate::backtesting::engine;
+use crate::backtesting::fill_model::FillModel;
+use crate::backtesting::report::{BacktestReport, PerformanceStats};
+use crate::backtesting::walk_forward::WalkForwardConfig;
+use crate::bar_aggregator::Timeframe;
+use crate::broker::{SimulatedBroker, TimeInForce};
+use crate::config::{EngineConfig, Stock};
+use crate::market_strategies::volume_filter::VolumeFilterConfig;
+use crate::stock_processing::entry_order::EntryOrder;
+use crate::stock_processing::sizing::Sizing;
+use crate::stock_processing::trailing_stop::TrailingStopConfig;
+use crate::StockMonitor;
+use anyhow::{Error, Result};
+use apca::data::v2::stream::Bar;
+use crossbeam_channel::unbounded;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Deserialize;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::create_dir_all;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use threadpool::ThreadPool;
+use tracing::{error, info};
+
+///Set under [optimizer] in the config, used when the bot is started with the "optimize" argument
+#[derive(Deserialize, Clone)]
+pub struct OptimizerConfig {
+    pub strategy: String,
+    //Every combination is backtested over all of these at once, sharing one account like a normal backtest
+    pub symbols: Vec<String>,
+    #[serde(default)]
+    pub metric: Metric,
+    #[serde(default)]
+    pub search: Search,
+    //How many combinations a random search tries
+    #[serde(default = "default_samples")]
+    pub samples: usize,
+    #[serde(default)]
+    pub seed: u64,
+    //How many of the best combinations get logged
+    #[serde(default = "default_top")]
+    pub top: usize,
+    pub emergency_limit: f64,
+    pub upper_limit: Option<f64>,
+    pub intensity: u32,
+    //Same as a stock's volume_filter and trailing_stop, applied to every combination
+    pub volume_filter: Option<VolumeFilterConfig>,
+    pub trailing_stop: Option<TrailingStopConfig>,
+    //Same as a stock's timeframe
+    #[serde(default)]
+    pub timeframe: Timeframe,
+    pub risk_per_trade: Option<f64>,
+    #[serde(default)]
+    pub sizing: Sizing,
+    #[serde(default)]
+    pub entry_order: EntryOrder,
+    #[serde(default)]
+    pub time_in_force: TimeInForce,
+    #[serde(default)]
+    pub extended_hours: bool,
+    #[serde(default)]
+    pub bracket_orders: bool,
+    #[serde(default)]
+    pub allow_short: bool,
+    pub params: BTreeMap<String, ParamRange>,
+    //Strategy params that stay the same in every combination, the strategy needs every key set between this and params
+    #[serde(default)]
+    pub strategy_params: toml::value::Table,
+    //Only needed for walk-forward mode
+    pub walk_forward: Option<WalkForwardConfig>,
+}
+
+fn default_samples() -> usize {
+    50
+}
+
+fn default_top() -> usize {
+    10
+}
+
+#[derive(Deserialize, Clone, Copy, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    #[default]
+    NetProfit,
+    Sharpe,
+    //Smallest max drawdown percent wins
+    MaxDrawdown,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Search {
+    //Every combination of every param
+    #[default]
+    Grid,
+    //Random combinations picked from the same grid
+    Random,
+}
+
+//Values from min to max (inclusive) in steps of step
+#[derive(Deserialize, Clone)]
+pub struct ParamRange {
+    pub min: f64,
+    pub max: f64,
+    #[serde(default = "default_step")]
+    pub step: f64,
+}
+
+fn default_step() -> f64 {
+    1.0
+}
+
+impl ParamRange {
+    fn values(&self) -> Result<Vec<toml::Value>> {
+        if self.step <= 0.0 || self.max < self.min {
+            return Err(Error::msg(format!(
+                "Bad param range min: {}, max: {}, step: {}",
+                self.min, self.max, self.step
+            )));
+        }
+        //Whole number ranges are passed to the strategy as integers so they fit params like periods
+        let integer = self.min.fract() == 0.0 && self.step.fract() == 0.0;
+        let count = ((self.max - self.min) / self.step + 1e-9).floor() as usize + 1;
+
+        Ok((0..count)
+            .map(|i| {
+                let value = self.min + self.step * i as f64;
+                if integer {
+                    toml::Value::Integer(value as i64)
+                } else {
+                    toml::Value::Float(value)
+                }
+            })
+            .collect())
+    }
+}
+
+///Everything needed to backtest one strategy setup over some bars
+#[derive(Clone)]
+pub struct Trial {
+    //Settings every monitor is built from, only the symbol and strategy_params change
+    pub stock: Stock,
+    pub symbols: Vec<String>,
+    pub starting_cash: f64,
+    pub fill_model: FillModel,
+}
+
+impl Trial {
+    ///Runs a fresh account and fresh monitors built with these params over the bars
+    ///The warm_up bars only go through the strategies first, nothing is traded on them
+    pub fn run(
+        &self,
+        params: &toml::Value,
+        warm_up: &[Bar],
+        bars: &[Bar],
+    ) -> Result<BacktestReport> {
+        let broker = Arc::new(SimulatedBroker::new(
+            Arc::new(RwLock::new(self.starting_cash)),
+            self.fill_model.clone(),
+        ));
+
+        let mut owned = vec![];
+        for symbol in &self.symbols {
+            owned.push(
+                self.stock
+                    .with(symbol, params.clone())
+                    .convert(true, broker.clone())?,
+            );
+        }
+        let mut monitors: BTreeMap<String, &mut StockMonitor> = owned
+            .iter_mut()
+            .map(|monitor| (monitor.symbol.clone(), monitor))
+            .collect();
+        for bar in warm_up {
+            monitors
+                .get_mut(&bar.symbol)
+                .unwrap()
+                .warm_up(bar.clone())?;
+        }
+
+        let result = engine::run_portfolio(&mut monitors, &broker, bars)?;
+        Ok(BacktestReport::new(self.starting_cash, &broker, result))
+    }
+}
+
+//One finished combination
+pub struct TrialResult {
+    pub params: toml::value::Table,
+    pub stats: PerformanceStats,
+}
+
+impl Metric {
+    //Orders results best first
+    pub fn compare(&self, a: &PerformanceStats, b: &PerformanceStats) -> Ordering {
+        match self {
+            Metric::NetProfit => b.net_pnl.total_cmp(&a.net_pnl),
+            //Runs without a Sharpe ratio (too few days or no movement) go last
+            Metric::Sharpe => match (a.sharpe, b.sharpe) {
+                (Some(a), Some(b)) => b.total_cmp(&a),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            },
+            Metric::MaxDrawdown => a.max_drawdown_pct.total_cmp(&b.max_drawdown_pct),
+        }
+    }
+}
+
+//Every combination of the param ranges, params are walked in name order
+fn grid(ranges: &BTreeMap<String, Vec<toml::Value>>) -> Vec<toml::value::Table> {
+    let mut combinations = vec![toml::value::Table::new()];
+    for (name, values) in ranges {
+        combinations = combinations
+            .into_iter()
+            .flat_map(|combination| {
+                values.iter().map(move |value| {
+                    let mut combination = combination.clone();
+                    combination.insert(name.clone(), value.clone());
+                    combination
+                })
+            })
+            .collect();
+    }
+    combinations
+}
+
+//Picks distinct random points of the grid, gives back the whole grid if it's smaller than the sample count
+fn random(
+    ranges: &BTreeMap<String, Vec<toml::Value>>,
+    samples: usize,
+    seed: u64,
+) -> Vec<toml::value::Table> {
+    let grid_size: usize = ranges
+        .values()
+        .map(|values| values.len())
+        .fold(1, usize::saturating_mul);
+    if grid_size <= samples {
+        return grid(ranges);
+    }
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut seen = HashSet::new();
+    let mut combinations = vec![];
+    while combinations.len() < samples {
+        let picks: Vec<usize> = ranges
+            .values()
+            .map(|values| rng.gen_range(0..values.len()))
+            .collect();
+        if seen.insert(picks.clone()) {
+            combinations.push(
+                ranges
+                    .iter()
+                    .zip(picks)
+                    .map(|((name, values), pick)| (name.clone(), values[pick].clone()))
+                    .collect(),
+            );
+        }
+    }
+    combinations
+}
+
+///Backtests the combinations on the thread pool and returns them best first by the metric
+pub fn sweep(
+    trial: &Trial,
+    combinations: Vec<toml::value::Table>,
+    bars: Arc<Vec<Bar>>,
+    metric: Metric,
+    threadpool: &ThreadPool,
+) -> Vec<TrialResult> {
+    let (tx, rx) = unbounded();
+    let total = combinations.len();
+    for params in combinations {
+        let tx = tx.clone();
+        let trial = trial.clone();
+        let bars = bars.clone();
+        threadpool.execute(move || {
+            let result = trial.run(&toml::Value::Table(params.clone()), &[], &bars);
+            let _ = tx.send((params, result));
+        });
+    }
+    drop(tx);
+
+    let mut results = vec![];
+    for (done, (params, result)) in rx.iter().enumerate() {
+        match result {
+            Ok(report) => results.push(TrialResult {
+                params,
+                stats: report.portfolio,
+            }),
+            Err(e) => error!("Combination {} failed: {:#?}", format_params(&params), e),
+        }
+        if (done + 1) % 10 == 0 {
+            info!("{}/{} combinations done", done + 1, total);
+        }
+    }
+
+    results.sort_by(|a, b| metric.compare(&a.stats, &b.stats));
+    results
+}
+
+impl OptimizerConfig {
+    ///Builds the combinations to try and the trial to run them with, fails early on bad ranges or param names
+    pub fn prepare(
+        &self,
+        engine_config: &EngineConfig,
+    ) -> Result<(Trial, Vec<toml::value::Table>)> {
+        let mut ranges = BTreeMap::new();
+        for (name, range) in &self.params {
+            ranges.insert(
+                name.clone(),
+                range
+                    .values()
+                    .map_err(|e| Error::msg(format!("[{}] {}", name, e)))?,
+            );
+        }
+        let combinations: Vec<toml::value::Table> = match self.search {
+            Search::Grid => grid(&ranges),
+            Search::Random => random(&ranges, self.samples, self.seed),
+        }
+        .into_iter()
+        .map(|swept| {
+            let mut combination = self.strategy_params.clone();
+            combination.extend(swept);
+            combination
+        })
+        .collect();
+
+        let trial = Trial {
+            stock: Stock::from_optimizer(self),
+            symbols: self.symbols.clone(),
+            starting_cash: engine_config.backtest_money,
+            fill_model: engine_config.fill_model.clone(),
+        };
+        //Every combination has to pass what a stock in the config would, caught before spending time on the whole sweep
+        for combination in &combinations {
+            trial
+                .stock
+                .with("optimizer", toml::Value::Table(combination.clone()))
+                .validate()
+                .map_err(|e| Error::msg(format!("{} ({})", e, format_params(combination))))?;
+        }
+
+        Ok((trial, combinations))
+    }
+}
+
+///Entry point of optimize mode, logs the best combinations and writes all of them to optimizer.csv
+pub fn run(config: &OptimizerConfig, engine_config: &EngineConfig) -> Result<()> {
+    let (trial, combinations) = config.prepare(engine_config)?;
+
+    let bars = Arc::new(engine::load_portfolio_bars(
+        &config.symbols,
+        engine_config.backtest_utc_offset,
+    )?);
+    info!(
+        "Optimizing {} over {} combinations of {:?}, ranked by {:?}",
+        &config.strategy,
+        combinations.len(),
+        config.params.keys().collect::<Vec<_>>(),
+        config.metric
+    );
+
+    let threadpool = ThreadPool::new(engine_config.threads);
+    let results = sweep(&trial, combinations, bars, config.metric, &threadpool);
+
+    for (rank, result) in results.iter().take(config.top).enumerate() {
+        info!(
+            "#{} {} net profit: {:.2}, sharpe: {:?}, max drawdown: {:.2}%, trades: {}",
+            rank + 1,
+            format_params(&result.params),
+            result.stats.net_pnl,
+            result.stats.sharpe,
+            result.stats.max_drawdown_pct,
+            result.stats.trades
+        );
+    }
+
+    write_results(
+        &engine_config.report_dir,
+        config.params.keys().cloned().collect(),
+        &results,
+    )
+}
+
+//name=value pairs for the logs
+pub fn format_params(params: &toml::value::Table) -> String {
+    params
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn write_results(dir: &str, param_names: Vec<String>, results: &[TrialResult]) -> Result<()> {
+    let dir = Path::new(dir);
+    create_dir_all(dir)?;
+    let path = dir.join("optimizer.csv");
+    let mut writer = csv::Writer::from_path(&path)?;
+
+    let mut header = vec!["rank".to_string()];
+    header.extend(param_names.iter().cloned());
+    header.extend(
+        [
+            "net_pnl",
+            "realized_pnl",
+            "unrealized_pnl",
+            "sharpe",
+            "sortino",
+            "max_drawdown",
+            "max_drawdown_pct",
+            "trades",
+            "wins",
+            "losses",
+        ]
+        .map(String::from),
+    );
+    writer.write_record(&header)?;
+
+    let optional = |value: Option<f64>| value.map(|v| v.to_string()).unwrap_or_default();
+    for (rank, result) in results.iter().enumerate() {
+        let stats = &result.stats;
+        let mut record = vec![(rank + 1).to_string()];
+        record.extend(param_names.iter().map(|name| {
+            result
+                .params
+                .get(name)
+                .map(|value| value.to_string())
+                .unwrap_or_default()
+        }));
+        record.extend([
+            stats.net_pnl.to_string(),
+            stats.realized_pnl.to_string(),
+            stats.unrealized_pnl.to_string(),
+            optional(stats.sharpe),
+            optional(stats.sortino),
+            stats.max_drawdown.to_string(),
+            stats.max_drawdown_pct.to_string(),
+            stats.trades.to_string(),
+            stats.wins.to_string(),
+            stats.losses.to_string(),
+        ]);
+        writer.write_record(&record)?;
+    }
+    writer.flush()?;
+
+    info!("Optimizer results written to {}", path.display());
+    Ok(())
+}
@@ -0,0 +1,490 @@
+use crate::backtesting::engine::BacktestResult;
+use crate::broker::{Fill, OrderSide, SimulatedBroker};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{create_dir_all, File};
+use std::path::Path;
+use tracing::info;
+
+//Used to annualize the daily returns for the Sharpe and Sortino ratios
+const TRADING_DAYS_PER_YEAR: f64 = 252.0;
+
+#[derive(Clone, Serialize)]
+pub struct EquityPoint {
+    pub time: DateTime<Utc>,
+    pub equity: f64,
+    //Market value of the positions held at this point
+    pub invested: f64,
+}
+
+//One buy matched with the sell that closed it, or a short sale with the buy that covered it
+#[derive(Clone, Serialize)]
+pub struct RoundTrip {
+    pub symbol: String,
+    pub short: bool,
+    pub entry_time: DateTime<Utc>,
+    pub exit_time: DateTime<Utc>,
+    pub quantity: f64,
+    pub entry_price: f64,
+    pub exit_price: f64,
+    pub commission: f64,
+    //Net of commission
+    pub pnl: f64,
+    pub return_pct: f64,
+    pub holding_minutes: i64,
+}
+
+#[derive(Clone, Serialize)]
+pub struct PerformanceStats {
+    //Symbol, or "portfolio" for the whole account
+    pub name: String,
+    pub realized_pnl: f64,
+    pub unrealized_pnl: f64,
+    pub net_pnl: f64,
+    pub max_drawdown: f64,
+    pub max_drawdown_pct: f64,
+    pub sharpe: Option<f64>,
+    pub sortino: Option<f64>,
+    //Percent of bars where a position was held, long or short
+    pub exposure_pct: f64,
+    pub trades: usize,
+    pub wins: usize,
+    pub losses: usize,
+    pub win_loss_ratio: Option<f64>,
+    pub average_holding_minutes: Option<f64>,
+}
+
+#[derive(Serialize)]
+pub struct SymbolReport {
+    pub stats: PerformanceStats,
+    //Starting cash plus the profit of this symbol alone
+    pub equity_curve: Vec<EquityPoint>,
+}
+
+#[derive(Serialize)]
+pub struct BacktestReport {
+    pub starting_cash: f64,
+    pub ending_equity: f64,
+    pub portfolio: PerformanceStats,
+    pub equity_curve: Vec<EquityPoint>,
+    pub symbols: Vec<SymbolReport>,
+    pub trades: Vec<RoundTrip>,
+    pub cash_contentions: usize,
+    //Charged on short positions, already part of the realized profits
+    pub borrow_fees: f64,
+}
+
+impl BacktestReport {
+    pub fn new(starting_cash: f64, broker: &SimulatedBroker, result: BacktestResult) -> Self {
+        let trades = round_trips(&broker.fills());
+
+        let mut symbols = vec![];
+        let mut realized_total = 0.0;
+        let mut unrealized_total = 0.0;
+        let mut borrow_fees = 0.0;
+        for (symbol, curve) in result.symbol_curves {
+            let realized = broker.realized_pnl(&symbol);
+            let unrealized = broker.unrealized_pnl(&symbol);
+            realized_total += realized;
+            unrealized_total += unrealized;
+            borrow_fees += broker.borrow_fees(&symbol);
+
+            let symbol_trades: Vec<&RoundTrip> = trades
+                .iter()
+                .filter(|trade| trade.symbol == symbol)
+                .collect();
+            symbols.push(SymbolReport {
+                stats: PerformanceStats::new(
+                    symbol,
+                    realized,
+                    unrealized,
+                    starting_cash,
+                    &curve,
+                    &symbol_trades,
+                ),
+                equity_curve: curve,
+            });
+        }
+
+        let portfolio = PerformanceStats::new(
+            "portfolio".to_string(),
+            realized_total,
+            unrealized_total,
+            starting_cash,
+            &result.equity_curve,
+            &trades.iter().collect::<Vec<&RoundTrip>>(),
+        );
+
+        Self {
+            starting_cash,
+            ending_equity: result
+                .equity_curve
+                .last()
+                .map(|point| point.equity)
+                .unwrap_or(starting_cash),
+            portfolio,
+            equity_curve: result.equity_curve,
+            symbols,
+            trades,
+            cash_contentions: result.contentions.len(),
+            borrow_fees,
+        }
+    }
+
+    ///Writes report.json with everything plus CSV files for the summary, trade log and equity curves into the given directory
+    pub fn write(&self, dir: &str) -> Result<()> {
+        let dir = Path::new(dir);
+        create_dir_all(dir)?;
+
+        serde_json::to_writer_pretty(File::create(dir.join("report.json"))?, self)?;
+
+        let mut summary = csv::Writer::from_path(dir.join("summary.csv"))?;
+        summary.serialize(&self.portfolio)?;
+        for symbol in &self.symbols {
+            summary.serialize(&symbol.stats)?;
+        }
+        summary.flush()?;
+
+        let mut trades = csv::Writer::from_path(dir.join("trades.csv"))?;
+        for trade in &self.trades {
+            trades.serialize(trade)?;
+        }
+        trades.flush()?;
+
+        write_curve(&dir.join("equity.csv"), &self.equity_curve)?;
+        for symbol in &self.symbols {
+            write_curve(
+                &dir.join(format!("equity_{}.csv", symbol.stats.name)),
+                &symbol.equity_curve,
+            )?;
+        }
+
+        info!("Backtest report written to {}", dir.display());
+        Ok(())
+    }
+}
+
+pub fn write_curve(path: &Path, curve: &[EquityPoint]) -> Result<()> {
+    let mut writer = csv::Writer::from_path(path)?;
+    for point in curve {
+        writer.serialize(point)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+impl PerformanceStats {
+    pub fn new(
+        name: String,
+        realized_pnl: f64,
+        unrealized_pnl: f64,
+        starting_equity: f64,
+        curve: &[EquityPoint],
+        trades: &[&RoundTrip],
+    ) -> Self {
+        let (max_drawdown, max_drawdown_pct) = max_drawdown(curve);
+        let returns = daily_returns(starting_equity, curve);
+
+        let wins = trades.iter().filter(|trade| trade.pnl > 0.0).count();
+        let losses = trades.iter().filter(|trade| trade.pnl < 0.0).count();
+
+        Self {
+            name,
+            realized_pnl,
+            unrealized_pnl,
+            net_pnl: realized_pnl + unrealized_pnl,
+            max_drawdown,
+            max_drawdown_pct,
+            sharpe: sharpe(&returns),
+            sortino: sortino(&returns),
+            exposure_pct: if curve.is_empty() {
+                0.0
+            } else {
+                curve.iter().filter(|point| point.invested != 0.0).count() as f64 * 100.0
+                    / curve.len() as f64
+            },
+            trades: trades.len(),
+            wins,
+            losses,
+            win_loss_ratio: if losses > 0 {
+                Some(wins as f64 / losses as f64)
+            } else {
+                None
+            },
+            average_holding_minutes: if trades.is_empty() {
+                None
+            } else {
+                Some(
+                    trades
+                        .iter()
+                        .map(|trade| trade.holding_minutes as f64)
+                        .sum::<f64>()
+                        / trades.len() as f64,
+                )
+            },
+        }
+    }
+}
+
+//Part of a buy that hasn't been sold yet, or of a short sale that hasn't been covered
+struct OpenLot {
+    time: DateTime<Utc>,
+    short: bool,
+    quantity: f64,
+    price: f64,
+    commission_per_share: f64,
+}
+
+///Matches every fill against the oldest open lots on the other side of the same symbol to build the round trip trade log
+///Whatever is left of a fill after that opens a new lot, sells with nothing held are short sales
+pub fn round_trips(fills: &[Fill]) -> Vec<RoundTrip> {
+    let mut open: BTreeMap<&str, VecDeque<OpenLot>> = BTreeMap::new();
+    let mut trades = vec![];
+
+    for fill in fills {
+        let lots = open.entry(&fill.symbol).or_default();
+        let short = fill.side == OrderSide::Sell;
+        let mut remaining = fill.quantity;
+        while remaining > 0.0 {
+            let Some(lot) = lots.front_mut().filter(|lot| lot.short != short) else {
+                break;
+            };
+            let quantity = remaining.min(lot.quantity);
+            let commission =
+                (lot.commission_per_share + fill.commission / fill.quantity) * quantity;
+            let direction = if lot.short { -1.0 } else { 1.0 };
+            let pnl = (fill.price - lot.price) * quantity * direction - commission;
+            trades.push(RoundTrip {
+                symbol: fill.symbol.clone(),
+                short: lot.short,
+                entry_time: lot.time,
+                exit_time: fill.time,
+                quantity,
+                entry_price: lot.price,
+                exit_price: fill.price,
+                commission,
+                pnl,
+                return_pct: pnl / (lot.price * quantity) * 100.0,
+                holding_minutes: (fill.time - lot.time).num_minutes(),
+            });
+
+            lot.quantity -= quantity;
+            remaining -= quantity;
+            if lot.quantity <= 0.0 {
+                lots.pop_front();
+            }
+        }
+
+        if remaining > 0.0 {
+            lots.push_back(OpenLot {
+                time: fill.time,
+                short,
+                quantity: remaining,
+                price: fill.price,
+                commission_per_share: fill.commission / fill.quantity,
+            });
+        }
+    }
+
+    trades
+}
+
+//Largest drop from a peak, in dollars and in percent of that peak
+fn max_drawdown(curve: &[EquityPoint]) -> (f64, f64) {
+    let mut peak = f64::MIN;
+    let mut worst = (0.0, 0.0);
+    for point in curve {
+        peak = peak.max(point.equity);
+        let drawdown = peak - point.equity;
+        if drawdown > worst.0 {
+            worst = (drawdown, drawdown / peak * 100.0);
+        }
+    }
+    worst
+}
+
+//Returns between the last equity of each day, the first day is measured against the starting equity
+fn daily_returns(starting_equity: f64, curve: &[EquityPoint]) -> Vec<f64> {
+    let mut day_closes: BTreeMap<_, f64> = BTreeMap::new();
+    for point in curve {
+        day_closes.insert(point.time.date_naive(), point.equity);
+    }
+
+    let mut previous = starting_equity;
+    day_closes
+        .values()
+        .map(|&equity| {
+            let daily_return = equity / previous - 1.0;
+            previous = equity;
+            daily_return
+        })
+        .collect()
+}
+
+fn sharpe(returns: &[f64]) -> Option<f64> {
+    if returns.len() < 2 {
+        return None;
+    }
+    let mean = returns.iter().sum::<f64>() / returns.len() as f64;
+    let variance =
+        returns.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / (returns.len() - 1) as f64;
+    if variance == 0.0 {
+        return None;
+    }
+    Some(mean / variance.sqrt() * TRADING_DAYS_PER_YEAR.sqrt())
+}
+
+//Like Sharpe but only days that lost money count as risk
+fn sortino(returns: &[f64]) -> Option<f64> {
+    if returns.len() < 2 {
+        return None;
+    }
+    let mean = returns.iter().sum::<f64>() / returns.len() as f64;
+    let downside = returns.iter().map(|r| r.min(0.0).powi(2)).sum::<f64>() / returns.len() as f64;
+    if downside == 0.0 {
+        return None;
+    }
+    Some(mean / downside.sqrt() * TRADING_DAYS_PER_YEAR.sqrt())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2022, 3, day, hour, minute, 0).unwrap()
+    }
+
+    fn fill(
+        time: DateTime<Utc>,
+        side: OrderSide,
+        quantity: f64,
+        price: f64,
+        commission: f64,
+    ) -> Fill {
+        Fill {
+            time,
+            symbol: "AAPL".to_string(),
+            side,
+            quantity,
+            price,
+            commission,
+        }
+    }
+
+    fn point(time: DateTime<Utc>, equity: f64) -> EquityPoint {
+        EquityPoint {
+            time,
+            equity,
+            invested: 0.0,
+        }
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn sells_close_the_oldest_lots_first_and_split_them() {
+        let trades = round_trips(&[
+            fill(at(1, 14, 0), OrderSide::Buy, 10.0, 100.0, 1.0),
+            fill(at(1, 15, 0), OrderSide::Buy, 5.0, 102.0, 0.0),
+            fill(at(2, 14, 0), OrderSide::Sell, 12.0, 105.0, 1.2),
+        ]);
+        assert_eq!(trades.len(), 2);
+
+        //All of the first lot, with its commission and its share of the sell's
+        assert!(close(trades[0].quantity, 10.0));
+        assert!(close(trades[0].entry_price, 100.0));
+        assert!(close(trades[0].commission, 2.0));
+        assert!(close(trades[0].pnl, 48.0));
+        assert_eq!(trades[0].holding_minutes, 24 * 60);
+        assert!(!trades[0].short);
+
+        //2 of the second lot's 5 shares
+        assert!(close(trades[1].quantity, 2.0));
+        assert!(close(trades[1].entry_price, 102.0));
+        assert!(close(trades[1].commission, 0.2));
+        assert!(close(trades[1].pnl, 5.8));
+    }
+
+    #[test]
+    fn sells_past_the_lots_open_a_short_that_buys_cover() {
+        let trades = round_trips(&[
+            fill(at(1, 14, 0), OrderSide::Buy, 3.0, 102.0, 0.0),
+            fill(at(1, 15, 0), OrderSide::Sell, 5.0, 101.0, 0.0),
+            fill(at(1, 16, 0), OrderSide::Buy, 2.0, 99.0, 0.0),
+        ]);
+        assert_eq!(trades.len(), 2);
+
+        assert!(!trades[0].short);
+        assert!(close(trades[0].quantity, 3.0));
+        assert!(close(trades[0].pnl, -3.0));
+
+        //The 2 shares left over were sold short at 101 and bought back at 99
+        assert!(trades[1].short);
+        assert!(close(trades[1].quantity, 2.0));
+        assert!(close(trades[1].entry_price, 101.0));
+        assert!(close(trades[1].exit_price, 99.0));
+        assert!(close(trades[1].pnl, 4.0));
+        assert!(close(trades[1].return_pct, 4.0 / 202.0 * 100.0));
+    }
+
+    #[test]
+    fn open_lots_are_not_trades() {
+        let trades = round_trips(&[
+            fill(at(1, 14, 0), OrderSide::Sell, 4.0, 50.0, 0.0),
+            fill(at(1, 15, 0), OrderSide::Sell, 1.0, 51.0, 0.0),
+        ]);
+        assert!(trades.is_empty());
+    }
+
+    #[test]
+    fn drawdown_is_the_largest_drop_from_a_peak() {
+        let curve: Vec<EquityPoint> = [100.0, 120.0, 90.0, 110.0, 80.0, 130.0, 117.0]
+            .iter()
+            .enumerate()
+            .map(|(i, &equity)| point(at(1, 14, i as u32), equity))
+            .collect();
+        let (dollars, percent) = max_drawdown(&curve);
+        assert!(close(dollars, 40.0));
+        assert!(close(percent, 40.0 / 120.0 * 100.0));
+    }
+
+    #[test]
+    fn no_drawdown_on_a_rising_curve() {
+        let curve = vec![point(at(1, 14, 0), 100.0), point(at(1, 14, 1), 101.0)];
+        assert_eq!(max_drawdown(&curve), (0.0, 0.0));
+        assert_eq!(max_drawdown(&[]), (0.0, 0.0));
+    }
+
+    #[test]
+    fn daily_returns_use_each_days_last_equity() {
+        let curve = vec![
+            point(at(1, 14, 0), 90.0),
+            point(at(1, 19, 59), 110.0),
+            point(at(2, 14, 0), 50.0),
+            point(at(2, 19, 59), 99.0),
+        ];
+        let returns = daily_returns(100.0, &curve);
+        assert_eq!(returns.len(), 2);
+        assert!(close(returns[0], 0.1));
+        assert!(close(returns[1], -0.1));
+    }
+
+    #[test]
+    fn ratios_need_two_days_and_some_risk() {
+        assert_eq!(sharpe(&[0.01]), None);
+        assert_eq!(sharpe(&[0.01, 0.01]), None);
+        assert_eq!(sortino(&[0.01, 0.02]), None);
+
+        //Mean 0.01, sample standard deviation 0.01
+        let sharpe = sharpe(&[0.0, 0.01, 0.02]).unwrap();
+        assert!(close(sharpe, TRADING_DAYS_PER_YEAR.sqrt()));
+        //Mean 0, so no reward for the risk taken
+        assert!(close(sortino(&[-0.01, 0.01]).unwrap(), 0.0));
+    }
+}
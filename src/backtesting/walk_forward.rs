@@ -0,0 +1,233 @@
+use crate::backtesting::engine;
+use crate::backtesting::optimizer::{format_params, sweep, OptimizerConfig};
+use crate::backtesting::report::{write_curve, EquityPoint, PerformanceStats, RoundTrip};
+use crate::config::EngineConfig;
+use anyhow::{Error, Result};
+use apca::data::v2::stream::Bar;
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use std::fs::{create_dir_all, File};
+use std::path::Path;
+use std::sync::Arc;
+use threadpool::ThreadPool;
+use tracing::{info, warn};
+
+///Set under [optimizer.walk_forward] in the config, used when the bot is started with the "walk-forward" argument
+#[derive(Deserialize, Clone)]
+pub struct WalkForwardConfig {
+    //Trading days the params are optimized on
+    pub in_sample_days: usize,
+    //Trading days right after that the winning params are tested on
+    pub out_of_sample_days: usize,
+    //How many trading days each window moves forward, defaults to out_of_sample_days so the tests line up back to back
+    //Can't be less than out_of_sample_days, overlapping tests would count the same days twice in the stitched results
+    pub step_days: Option<usize>,
+}
+
+//One in-sample/out-of-sample pair
+#[derive(Serialize)]
+pub struct WindowResult {
+    pub in_sample_start: NaiveDate,
+    pub in_sample_end: NaiveDate,
+    pub out_of_sample_start: NaiveDate,
+    pub out_of_sample_end: NaiveDate,
+    pub params: String,
+    pub in_sample_net_pnl: f64,
+    pub in_sample_sharpe: Option<f64>,
+    pub out_of_sample_net_pnl: f64,
+    pub out_of_sample_sharpe: Option<f64>,
+    pub out_of_sample_max_drawdown_pct: f64,
+    pub out_of_sample_trades: usize,
+}
+
+#[derive(Serialize)]
+pub struct WalkForwardReport {
+    pub starting_cash: f64,
+    pub windows: Vec<WindowResult>,
+    //Performance of all the out-of-sample windows run back to back, this is the number to trust
+    pub out_of_sample: PerformanceStats,
+    pub equity_curve: Vec<EquityPoint>,
+    pub trades: Vec<RoundTrip>,
+}
+
+//Splits the trading days into (in-sample, out-of-sample) day ranges
+fn windows(
+    days: &[NaiveDate],
+    config: &WalkForwardConfig,
+) -> Result<Vec<(Vec<NaiveDate>, Vec<NaiveDate>)>> {
+    let step = config.step_days.unwrap_or(config.out_of_sample_days);
+    if config.in_sample_days == 0 || config.out_of_sample_days == 0 || step == 0 {
+        return Err(Error::msg("Walk forward window sizes must be above 0"));
+    }
+    if step < config.out_of_sample_days {
+        return Err(Error::msg(format!(
+            "step_days ({}) can't be less than out_of_sample_days ({}), the out-of-sample windows would overlap",
+            step, config.out_of_sample_days
+        )));
+    }
+
+    let mut windows = vec![];
+    let mut start = 0;
+    while start + config.in_sample_days + config.out_of_sample_days <= days.len() {
+        let split = start + config.in_sample_days;
+        windows.push((
+            days[start..split].to_vec(),
+            days[split..split + config.out_of_sample_days].to_vec(),
+        ));
+        start += step;
+    }
+
+    if windows.is_empty() {
+        return Err(Error::msg(format!(
+            "Only {} trading days of data, need at least {} for one walk forward window",
+            days.len(),
+            config.in_sample_days + config.out_of_sample_days
+        )));
+    }
+    Ok(windows)
+}
+
+fn bars_on(bars: &[Bar], days: &[NaiveDate]) -> Vec<Bar> {
+    bars.iter()
+        .filter(|bar| days.contains(&bar.timestamp.date_naive()))
+        .cloned()
+        .collect()
+}
+
+///Entry point of walk-forward mode, optimizes on each in-sample window and tests the winner on the window after it
+pub fn run(config: &OptimizerConfig, engine_config: &EngineConfig) -> Result<()> {
+    let walk_forward = config
+        .walk_forward
+        .as_ref()
+        .ok_or_else(|| Error::msg("No [optimizer.walk_forward] section in Config.toml"))?;
+    let (trial, combinations) = config.prepare(engine_config)?;
+    let bars = engine::load_portfolio_bars(&config.symbols, engine_config.backtest_utc_offset)?;
+
+    let mut days: Vec<NaiveDate> = bars.iter().map(|bar| bar.timestamp.date_naive()).collect();
+    days.dedup();
+    let windows = windows(&days, walk_forward)?;
+    info!(
+        "Walking {} forward over {} windows, {} combinations each, ranked by {:?}",
+        &config.strategy,
+        windows.len(),
+        combinations.len(),
+        config.metric
+    );
+
+    let threadpool = ThreadPool::new(engine_config.threads);
+    let starting_cash = engine_config.backtest_money;
+    let mut results = vec![];
+    let mut equity_curve: Vec<EquityPoint> = vec![];
+    let mut trades = vec![];
+    let mut realized = 0.0;
+    let mut unrealized = 0.0;
+
+    for (in_sample, out_of_sample) in windows {
+        let in_sample_bars = Arc::new(bars_on(&bars, &in_sample));
+        let ranked = sweep(
+            &trial,
+            combinations.clone(),
+            in_sample_bars.clone(),
+            config.metric,
+            &threadpool,
+        );
+        let Some(best) = ranked.into_iter().next() else {
+            warn!(
+                "No combination finished for the window starting {}, skipping it",
+                in_sample[0]
+            );
+            continue;
+        };
+
+        //Every window starts with fresh monitors and the starting cash, its profit is stacked on top of the windows before it
+        //The in sample bars warm the strategies up first so they can trade from the first out of sample bar
+        let report = trial.run(
+            &toml::Value::Table(best.params.clone()),
+            &in_sample_bars,
+            &bars_on(&bars, &out_of_sample),
+        )?;
+        let carried = equity_curve
+            .last()
+            .map(|point| point.equity)
+            .unwrap_or(starting_cash)
+            - starting_cash;
+        equity_curve.extend(report.equity_curve.iter().map(|point| EquityPoint {
+            time: point.time,
+            equity: point.equity + carried,
+            invested: point.invested,
+        }));
+        realized += report.portfolio.realized_pnl;
+        unrealized += report.portfolio.unrealized_pnl;
+        trades.extend(report.trades);
+
+        info!(
+            "[{} to {}] {} made {:.2} in sample, {:.2} out of sample",
+            out_of_sample[0],
+            out_of_sample[out_of_sample.len() - 1],
+            format_params(&best.params),
+            best.stats.net_pnl,
+            report.portfolio.net_pnl
+        );
+        results.push(WindowResult {
+            in_sample_start: in_sample[0],
+            in_sample_end: in_sample[in_sample.len() - 1],
+            out_of_sample_start: out_of_sample[0],
+            out_of_sample_end: out_of_sample[out_of_sample.len() - 1],
+            params: format_params(&best.params),
+            in_sample_net_pnl: best.stats.net_pnl,
+            in_sample_sharpe: best.stats.sharpe,
+            out_of_sample_net_pnl: report.portfolio.net_pnl,
+            out_of_sample_sharpe: report.portfolio.sharpe,
+            out_of_sample_max_drawdown_pct: report.portfolio.max_drawdown_pct,
+            out_of_sample_trades: report.portfolio.trades,
+        });
+    }
+
+    let out_of_sample = PerformanceStats::new(
+        "out_of_sample".to_string(),
+        realized,
+        unrealized,
+        starting_cash,
+        &equity_curve,
+        &trades.iter().collect::<Vec<&RoundTrip>>(),
+    );
+    info!(
+        "Stitched out of sample: net profit: {:.2}, sharpe: {:?}, max drawdown: {:.2}%, trades: {}, wins: {}, losses: {}",
+        out_of_sample.net_pnl,
+        out_of_sample.sharpe,
+        out_of_sample.max_drawdown_pct,
+        out_of_sample.trades,
+        out_of_sample.wins,
+        out_of_sample.losses
+    );
+
+    WalkForwardReport {
+        starting_cash,
+        windows: results,
+        out_of_sample,
+        equity_curve,
+        trades,
+    }
+    .write(&engine_config.report_dir)
+}
+
+impl WalkForwardReport {
+    ///Writes walk_forward.json with everything, plus the windows and the stitched equity curve as CSV
+    pub fn write(&self, dir: &str) -> Result<()> {
+        let dir = Path::new(dir);
+        create_dir_all(dir)?;
+
+        serde_json::to_writer_pretty(File::create(dir.join("walk_forward.json"))?, self)?;
+
+        let mut windows = csv::Writer::from_path(dir.join("walk_forward.csv"))?;
+        for window in &self.windows {
+            windows.serialize(window)?;
+        }
+        windows.flush()?;
+
+        write_curve(&dir.join("walk_forward_equity.csv"), &self.equity_curve)?;
+
+        info!("Walk forward report written to {}", dir.display());
+        Ok(())
+    }
+}
@@ -0,0 +1,260 @@
+use apca::data::v2::stream::Bar;
+use chrono::{DateTime, Duration, NaiveDate, NaiveTime, TimeZone, Utc};
+use chrono_tz::America::New_York;
+use serde::{Deserialize, Serialize};
+
+///The bar size a strategy runs on, set as timeframe on a stock in the config
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+pub enum Timeframe {
+    //What the alpaca stream and the backtest_data files give, bars are passed through as they are
+    #[default]
+    #[serde(rename = "1m")]
+    OneMinute,
+    #[serde(rename = "5m")]
+    FiveMinutes,
+    #[serde(rename = "15m")]
+    FifteenMinutes,
+    #[serde(rename = "30m")]
+    ThirtyMinutes,
+    #[serde(rename = "1h")]
+    OneHour,
+    //The whole regular session
+    #[serde(rename = "1d")]
+    OneDay,
+}
+
+impl Timeframe {
+    //None for the daily bar, it's as long as the session
+    fn minutes(&self) -> Option<i64> {
+        match self {
+            Timeframe::OneMinute => Some(1),
+            Timeframe::FiveMinutes => Some(5),
+            Timeframe::FifteenMinutes => Some(15),
+            Timeframe::ThirtyMinutes => Some(30),
+            Timeframe::OneHour => Some(60),
+            Timeframe::OneDay => None,
+        }
+    }
+}
+
+//Regular session in New York time, the buckets are counted from the open so an hour bar is 9:30 to 10:30 and the last one of the day is cut short at the close
+fn session_open() -> NaiveTime {
+    NaiveTime::from_hms_opt(9, 30, 0).unwrap()
+}
+
+fn session_close() -> NaiveTime {
+    NaiveTime::from_hms_opt(16, 0, 0).unwrap()
+}
+
+//Which bar a minute goes in, the same key means the same bar
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Bucket {
+    date: NaiveDate,
+    index: i64,
+    ends: DateTime<Utc>,
+}
+
+///Builds bars of a higher timeframe out of 1 minute bars
+///Minutes outside the regular session are left out of anything above 1m, a bar is handed out on its last minute or, when that minute never traded, on the first minute of the next bar
+pub struct BarAggregator {
+    timeframe: Timeframe,
+    building: Option<(Bucket, Bar)>,
+}
+
+impl BarAggregator {
+    pub fn new(timeframe: Timeframe) -> Self {
+        Self {
+            timeframe,
+            building: None,
+        }
+    }
+
+    fn bucket(&self, time: DateTime<Utc>) -> Option<Bucket> {
+        let local = time.with_timezone(&New_York);
+        let date = local.date_naive();
+        let (open, close) = (session_open(), session_close());
+        if local.time() < open || local.time() >= close {
+            return None;
+        }
+        let close_at = |time: NaiveTime| {
+            New_York
+                .from_local_datetime(&date.and_time(time))
+                .single()
+                .map(|time| time.with_timezone(&Utc))
+        };
+
+        let (index, ends) = match self.timeframe.minutes() {
+            Some(minutes) => {
+                let index = (local.time() - open).num_minutes() / minutes;
+                let ends = (open + Duration::minutes((index + 1) * minutes)).min(close);
+                (index, ends)
+            }
+            None => (0, close),
+        };
+        Some(Bucket {
+            date,
+            index,
+            ends: close_at(ends)?,
+        })
+    }
+
+    ///Takes the next 1 minute bar of the symbol and gives back the bars that finished with it, oldest first
+    ///Finished bars have the time stamp of the last minute in them, so whoever gets them sees the time the bar was done
+    pub fn push(&mut self, minute: &Bar) -> Vec<Bar> {
+        if self.timeframe == Timeframe::OneMinute {
+            return vec![minute.clone()];
+        }
+        let Some(bucket) = self.bucket(minute.timestamp) else {
+            return vec![];
+        };
+
+        let mut finished = vec![];
+        match &mut self.building {
+            Some((building, bar)) if *building == bucket => {
+                if minute.high_price > bar.high_price {
+                    bar.high_price = minute.high_price.clone();
+                }
+                if minute.low_price < bar.low_price {
+                    bar.low_price = minute.low_price.clone();
+                }
+                bar.close_price = minute.close_price.clone();
+                bar.volume += minute.volume;
+                bar.timestamp = minute.timestamp;
+            }
+            _ => {
+                //The last bar never saw its final minute, it's done now that a later one came in
+                if let Some((_, bar)) = self.building.replace((bucket, minute.clone())) {
+                    finished.push(bar);
+                }
+            }
+        }
+
+        if minute.timestamp + Duration::minutes(1) >= bucket.ends {
+            if let Some((_, bar)) = self.building.take() {
+                finished.push(bar);
+            }
+        }
+        finished
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Timelike;
+    use num_decimal::Num;
+
+    //June is daylight saving, New York is UTC-4
+    fn minute(day: u32, hour: u32, minute: u32, price: i64) -> Bar {
+        Bar {
+            symbol: "AAPL".to_string(),
+            open_price: Num::from(price),
+            high_price: Num::from(price + 1),
+            low_price: Num::from(price - 1),
+            close_price: Num::from(price),
+            volume: 10,
+            timestamp: New_York
+                .with_ymd_and_hms(2022, 6, day, hour, minute, 0)
+                .unwrap()
+                .with_timezone(&Utc),
+        }
+    }
+
+    fn ny_time(bar: &Bar) -> (u32, u32) {
+        let local = bar.timestamp.with_timezone(&New_York);
+        (local.time().hour(), local.time().minute())
+    }
+
+    #[test]
+    fn one_minute_bars_pass_through() {
+        let mut aggregator = BarAggregator::new(Timeframe::OneMinute);
+        //Even outside the session
+        let bar = minute(1, 8, 0, 100);
+        assert_eq!(aggregator.push(&bar), vec![bar]);
+    }
+
+    #[test]
+    fn hour_bars_start_at_the_open() {
+        let mut aggregator = BarAggregator::new(Timeframe::OneHour);
+        assert!(aggregator.push(&minute(1, 9, 29, 50)).is_empty());
+
+        let mut finished = vec![];
+        for i in 0..60 {
+            let (hour, min) = (9 + (30 + i) / 60, (30 + i) % 60);
+            finished.extend(aggregator.push(&minute(1, hour, min, 100 + i as i64)));
+            if i < 59 {
+                assert!(finished.is_empty(), "finished early at {}:{}", hour, min);
+            }
+        }
+
+        //Handed out on 10:29, the last minute in it
+        assert_eq!(finished.len(), 1);
+        let bar = &finished[0];
+        assert_eq!(ny_time(bar), (10, 29));
+        assert_eq!(bar.open_price, Num::from(100));
+        assert_eq!(bar.close_price, Num::from(159));
+        assert_eq!(bar.high_price, Num::from(160));
+        assert_eq!(bar.low_price, Num::from(99));
+        assert_eq!(bar.volume, 600);
+
+        //10:30 starts the next one
+        assert!(aggregator.push(&minute(1, 10, 30, 100)).is_empty());
+    }
+
+    #[test]
+    fn last_bar_of_the_day_is_cut_short_at_the_close() {
+        let mut aggregator = BarAggregator::new(Timeframe::OneHour);
+        assert!(aggregator.push(&minute(1, 15, 30, 100)).is_empty());
+        assert!(aggregator.push(&minute(1, 15, 58, 101)).is_empty());
+
+        let finished = aggregator.push(&minute(1, 15, 59, 102));
+        assert_eq!(finished.len(), 1);
+        assert_eq!(finished[0].open_price, Num::from(100));
+        assert_eq!(finished[0].close_price, Num::from(102));
+        assert_eq!(finished[0].volume, 30);
+
+        //After hours is left out
+        assert!(aggregator.push(&minute(1, 16, 0, 103)).is_empty());
+    }
+
+    #[test]
+    fn missing_last_minute_finishes_on_the_next_bar() {
+        let mut aggregator = BarAggregator::new(Timeframe::FiveMinutes);
+        for min in 30..34 {
+            assert!(aggregator.push(&minute(1, 9, min, 100)).is_empty());
+        }
+
+        //9:34 never traded
+        let finished = aggregator.push(&minute(1, 9, 35, 200));
+        assert_eq!(finished.len(), 1);
+        assert_eq!(ny_time(&finished[0]), (9, 33));
+        assert_eq!(finished[0].close_price, Num::from(100));
+        assert_eq!(finished[0].volume, 40);
+
+        //9:35 went into the next bar, not the one handed out
+        let finished = aggregator.push(&minute(1, 9, 39, 300));
+        assert_eq!(finished.len(), 1);
+        assert_eq!(finished[0].open_price, Num::from(200));
+        assert_eq!(finished[0].close_price, Num::from(300));
+    }
+
+    #[test]
+    fn day_bars_cover_the_session() {
+        let mut aggregator = BarAggregator::new(Timeframe::OneDay);
+        assert!(aggregator.push(&minute(1, 9, 30, 100)).is_empty());
+        assert!(aggregator.push(&minute(1, 12, 0, 120)).is_empty());
+        let finished = aggregator.push(&minute(1, 15, 59, 110));
+        assert_eq!(finished.len(), 1);
+        assert_eq!(finished[0].open_price, Num::from(100));
+        assert_eq!(finished[0].high_price, Num::from(121));
+        assert_eq!(finished[0].close_price, Num::from(110));
+
+        //A day without a 15:59 bar is handed out when the next day opens
+        assert!(aggregator.push(&minute(2, 9, 30, 100)).is_empty());
+        assert!(aggregator.push(&minute(2, 15, 58, 105)).is_empty());
+        let finished = aggregator.push(&minute(3, 9, 30, 90));
+        assert_eq!(finished.len(), 1);
+        assert_eq!(finished[0].close_price, Num::from(105));
+        assert_eq!(ny_time(&finished[0]), (15, 58));
+    }
+}
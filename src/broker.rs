@@ -0,0 +1,959 @@
+use crate::alpaca_api::{APIThreadReq, APIThreadRes};
+use crate::backtesting::fill_model::{FillModel, FillTiming};
+use anyhow::{Error, Result};
+use apca::data::v2::stream::Bar;
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::America::New_York;
+use crossbeam_channel::{unbounded, Sender};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use tracing::{info, warn};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OrderSide {
+    Buy,
+    Sell,
+}
+
+//Everything a broker needs to know to place an order for a monitor
+#[derive(Clone, Debug)]
+pub struct OrderRequest {
+    pub symbol: String,
+    pub side: OrderSide,
+    //Can be fractional
+    pub quantity: f64,
+    //The last price the monitor saw, simulated brokers fill at this price while Alpaca ignores it for market orders
+    pub reference_price: f64,
+    pub order_type: OrderType,
+    pub time_in_force: TimeInForce,
+    //Lets the order fill in the pre and after market sessions, alpaca only takes it on day limit orders
+    pub extended_hours: bool,
+    //Exit orders the broker holds for the position this order opens
+    pub bracket: Option<Bracket>,
+}
+
+//How the price of an order is set
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OrderType {
+    Market,
+    //Fills at the limit price or better
+    Limit { limit_price: f64 },
+    //Turns into a market order once the price gets to the stop
+    Stop { stop_price: f64 },
+    //Turns into a limit order once the price gets to the stop
+    StopLimit { stop_price: f64, limit_price: f64 },
+}
+
+///How long an order waits to fill, set as time_in_force on a stock in the config
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeInForce {
+    //Until the end of the session it was placed in
+    #[default]
+    Day,
+    //Until canceled
+    Gtc,
+    //Fills what it can right away and cancels the rest
+    Ioc,
+    //Only fills at the next open
+    Opg,
+    //Only fills at the close
+    Cls,
+}
+
+//Prices the broker closes the position at, whichever is hit first fills and the other gets canceled
+//Needs at least one of them
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bracket {
+    pub take_profit: Option<f64>,
+    pub stop_loss: Option<f64>,
+}
+
+//What the broker gave back for a new order
+#[derive(Clone, Debug)]
+pub struct SubmittedOrder {
+    pub id: String,
+    //IDs of the bracket's exit orders, empty without a bracket
+    pub legs: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OrderStatus {
+    //Waiting to fill
+    Open,
+    //Some of it filled, the rest is still waiting
+    PartiallyFilled,
+    Filled,
+    //Canceled or expired, what filled before that stays filled
+    Canceled,
+    //The broker wouldn't take it
+    Rejected,
+}
+
+//An order as the broker sees it now
+#[derive(Clone, Debug)]
+pub struct BrokerOrder {
+    //The replacing order's ID when the one asked for was replaced, its fills count toward this one
+    pub id: String,
+    pub status: OrderStatus,
+    //What has been executed so far
+    pub filled_quantity: f64,
+    pub filled_avg_price: Option<f64>,
+}
+
+//An order the broker is still working on, what the startup check compares with the monitors
+#[derive(Clone, Debug)]
+pub struct OpenOrder {
+    pub id: String,
+    pub symbol: String,
+    pub side: OrderSide,
+}
+
+//A position as reported by the broker
+#[derive(Clone, Debug)]
+pub struct BrokerPosition {
+    pub symbol: String,
+    //Negative when short
+    pub quantity: f64,
+    pub average_entry_price: f64,
+}
+
+//This trait is what the stock monitors talk to when they want to trade, it lets the same buy/sell code run live, in paper mode and in backtests
+pub trait Broker {
+    fn submit_order(&self, order: OrderRequest) -> Result<SubmittedOrder>;
+    fn cancel_order(&self, order_id: &str) -> Result<()>;
+    fn order(&self, order_id: &str) -> Result<BrokerOrder>;
+    fn positions(&self) -> Result<Vec<BrokerPosition>>;
+    //Every order that can still fill, bracket legs included
+    fn open_orders(&self) -> Result<Vec<OpenOrder>>;
+    fn cash(&self) -> Result<f64>;
+    //Cash plus the value of every position, shorts count against it
+    fn equity(&self) -> Result<f64>;
+}
+
+///Forwards every call to the alpaca API thread and waits for its answer, used for both live and paper trading
+pub struct AlpacaBroker {
+    api_tx: Sender<(APIThreadReq, Sender<APIThreadRes>)>,
+}
+
+impl AlpacaBroker {
+    pub fn new(api_tx: Sender<(APIThreadReq, Sender<APIThreadRes>)>) -> Self {
+        Self { api_tx }
+    }
+
+    fn request(&self, req: APIThreadReq) -> Result<APIThreadRes> {
+        let (res_tx, res_rx) = unbounded();
+        self.api_tx
+            .send((req, res_tx))
+            .map_err(|_| Error::msg("Alpaca API thread is not running"))?;
+
+        match res_rx.recv()? {
+            APIThreadRes::Error { error } => Err(error),
+            res => Ok(res),
+        }
+    }
+}
+
+impl Broker for AlpacaBroker {
+    fn submit_order(&self, order: OrderRequest) -> Result<SubmittedOrder> {
+        match self.request(APIThreadReq::SubmitOrder { order })? {
+            APIThreadRes::OrderSubmitted { order } => Ok(order),
+            _ => Err(Error::msg("Unexpected response from alpaca API thread")),
+        }
+    }
+
+    fn cancel_order(&self, order_id: &str) -> Result<()> {
+        match self.request(APIThreadReq::CancelOrder {
+            order_id: order_id.to_string(),
+        })? {
+            APIThreadRes::Processed => Ok(()),
+            _ => Err(Error::msg("Unexpected response from alpaca API thread")),
+        }
+    }
+
+    fn order(&self, order_id: &str) -> Result<BrokerOrder> {
+        match self.request(APIThreadReq::GetOrder {
+            order_id: order_id.to_string(),
+        })? {
+            APIThreadRes::Order { order } => Ok(order),
+            _ => Err(Error::msg("Unexpected response from alpaca API thread")),
+        }
+    }
+
+    fn positions(&self) -> Result<Vec<BrokerPosition>> {
+        match self.request(APIThreadReq::GetPositions)? {
+            APIThreadRes::Positions { positions } => Ok(positions),
+            _ => Err(Error::msg("Unexpected response from alpaca API thread")),
+        }
+    }
+
+    fn open_orders(&self) -> Result<Vec<OpenOrder>> {
+        match self.request(APIThreadReq::GetOpenOrders)? {
+            APIThreadRes::OpenOrders { orders } => Ok(orders),
+            _ => Err(Error::msg("Unexpected response from alpaca API thread")),
+        }
+    }
+
+    fn cash(&self) -> Result<f64> {
+        match self.request(APIThreadReq::GetCash)? {
+            APIThreadRes::Cash { cash } => Ok(cash),
+            _ => Err(Error::msg("Unexpected response from alpaca API thread")),
+        }
+    }
+
+    fn equity(&self) -> Result<f64> {
+        match self.request(APIThreadReq::GetEquity)? {
+            APIThreadRes::Equity { equity } => Ok(equity),
+            _ => Err(Error::msg("Unexpected response from alpaca API thread")),
+        }
+    }
+}
+
+///In process broker used for backtesting, fills are priced by the configured fill model
+pub struct SimulatedBroker {
+    //Shared with the ticker loop so it can report profit the same way it does for live trading
+    cash: Arc<RwLock<f64>>,
+    fill_model: FillModel,
+    book: Mutex<SimulatedBook>,
+}
+
+//An executed simulated order, the backtest report builds its trade log from these
+#[derive(Clone, Debug)]
+pub struct Fill {
+    pub time: DateTime<Utc>,
+    pub symbol: String,
+    pub side: OrderSide,
+    pub quantity: f64,
+    pub price: f64,
+    pub commission: f64,
+}
+
+#[derive(Default)]
+struct SimulatedBook {
+    positions: HashMap<String, BrokerPosition>,
+    next_order_id: u64,
+    //Time of the last bar marked, fills are stamped with this time
+    now: Option<DateTime<Utc>>,
+    last_prices: HashMap<String, f64>,
+    last_volumes: HashMap<String, f64>,
+    realized: HashMap<String, f64>,
+    fills: Vec<Fill>,
+    //Time of the last bar of each symbol, borrow fees are charged for the time between bars
+    last_marks: HashMap<String, DateTime<Utc>>,
+    borrow_fees: HashMap<String, f64>,
+    //Every order submitted, by ID
+    orders: HashMap<String, BrokerOrder>,
+    //Orders that didn't fill when they were placed, waiting on later bars of their symbol
+    resting: Vec<RestingOrder>,
+}
+
+//An order waiting at the simulated broker, bracket legs wait for their entry to fill first
+struct RestingOrder {
+    id: String,
+    order: OrderRequest,
+    //New York session day it was placed in, day orders expire after it
+    placed_on: NaiveDate,
+    //ID of the entry for bracket legs, the legs of one entry cancel each other
+    parent: Option<String>,
+}
+
+//What a resting order does on a bar of its symbol
+enum Outcome {
+    Fill(f64),
+    Wait,
+    Expire,
+}
+
+fn session_day(time: DateTime<Utc>) -> NaiveDate {
+    time.with_timezone(&New_York).date_naive()
+}
+
+//Price an order fills at on a bar, None if the bar never got to its price, a bar that opens past the price fills at the open
+//An order placed at a price is the same as a bar that never moved from it
+fn bar_fill_price(order: &OrderRequest, open: f64, high: f64, low: f64) -> Option<f64> {
+    let buy = order.side == OrderSide::Buy;
+    //Buy limits wait for the price to come down to them, sell limits for it to come up
+    let limit = |limit: f64| {
+        if buy {
+            (low <= limit).then_some(open.min(limit))
+        } else {
+            (high >= limit).then_some(open.max(limit))
+        }
+    };
+    //Stops are the other way round
+    let stop = |stop: f64| {
+        if buy {
+            (high >= stop).then_some(open.max(stop))
+        } else {
+            (low <= stop).then_some(open.min(stop))
+        }
+    };
+    match order.order_type {
+        OrderType::Market => Some(open),
+        OrderType::Limit { limit_price } => limit(limit_price),
+        OrderType::Stop { stop_price } => stop(stop_price),
+        OrderType::StopLimit {
+            stop_price,
+            limit_price,
+        } => stop(stop_price).filter(|price| {
+            if buy {
+                *price <= limit_price
+            } else {
+                *price >= limit_price
+            }
+        }),
+    }
+}
+
+//Used to turn the yearly borrow rate into a charge for the time a short was held
+const SECONDS_PER_YEAR: f64 = 365.0 * 24.0 * 60.0 * 60.0;
+
+impl SimulatedBook {
+    //Charges a short position in the symbol for the time since its last bar at the yearly rate (in percent) of its value, nights and weekends included
+    fn charge_borrow(&mut self, cash: &mut f64, rate: f64, symbol: &str, now: DateTime<Utc>) {
+        let Some(since) = self.last_marks.insert(symbol.to_string(), now) else {
+            return;
+        };
+        let shorted = match (self.positions.get(symbol), self.last_prices.get(symbol)) {
+            (Some(position), Some(price)) if position.quantity < 0.0 => -position.quantity * price,
+            _ => return,
+        };
+        let fee = shorted * rate / 100.0 * (now - since).num_seconds() as f64 / SECONDS_PER_YEAR;
+        if fee <= 0.0 {
+            return;
+        }
+        *cash -= fee;
+        *self.realized.entry(symbol.to_string()).or_insert(0.0) -= fee;
+        *self.borrow_fees.entry(symbol.to_string()).or_insert(0.0) += fee;
+    }
+
+    //Gives out the next order ID and keeps the order as open until it fills
+    fn new_order(&mut self) -> String {
+        self.next_order_id += 1;
+        let order_id = format!("sim-{}", self.next_order_id);
+        self.orders.insert(
+            order_id.clone(),
+            BrokerOrder {
+                id: order_id.clone(),
+                status: OrderStatus::Open,
+                filled_quantity: 0.0,
+                filled_avg_price: None,
+            },
+        );
+        order_id
+    }
+
+    //Executes the order and keeps its status up to date, an entry that can't fill takes its legs down with it
+    fn fill(
+        &mut self,
+        cash: &mut f64,
+        fill_model: &FillModel,
+        order_id: &str,
+        order: &OrderRequest,
+        bar_price: f64,
+        bar_volume: f64,
+    ) -> Result<()> {
+        let result = self.execute(cash, fill_model, order, bar_price, bar_volume);
+        if let Some(record) = self.orders.get_mut(order_id) {
+            match &result {
+                Ok(price) => {
+                    record.status = OrderStatus::Filled;
+                    record.filled_quantity = order.quantity;
+                    record.filled_avg_price = Some(*price);
+                }
+                Err(_) => record.status = OrderStatus::Rejected,
+            }
+        }
+        if result.is_err() {
+            self.cancel_legs(order_id);
+        }
+        result.map(|_| ())
+    }
+
+    fn cancel_legs(&mut self, parent: &str) {
+        let (canceled, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut self.resting)
+            .into_iter()
+            .partition(|resting| resting.parent.as_deref() == Some(parent));
+        self.resting = kept;
+        for leg in canceled {
+            if let Some(record) = self.orders.get_mut(&leg.id) {
+                record.status = OrderStatus::Canceled;
+            }
+        }
+    }
+
+    //Marks the order and its bracket legs as canceled
+    fn cancel(&mut self, order_id: &str) {
+        if let Some(record) = self.orders.get_mut(order_id) {
+            record.status = OrderStatus::Canceled;
+        }
+        self.cancel_legs(order_id);
+    }
+
+    //Works the resting orders of the bar's symbol, stops go first since a bar that reached a stop loss and a take profit can't tell which came first
+    //A filled leg cancels the other legs of its entry
+    fn work_resting(
+        &mut self,
+        cash: &mut f64,
+        fill_model: &FillModel,
+        bar: &Bar,
+        previous_close: Option<f64>,
+    ) {
+        let today = session_day(bar.timestamp);
+        let open = bar.open_price.to_f64().unwrap_or_default();
+        let high = bar.high_price.to_f64().unwrap_or_default();
+        let low = bar.low_price.to_f64().unwrap_or_default();
+
+        let (mut ready, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut self.resting)
+            .into_iter()
+            .partition(|resting| {
+                resting.order.symbol == bar.symbol
+                    && resting.parent.as_ref().is_none_or(|parent| {
+                        self.orders
+                            .get(parent)
+                            .is_some_and(|entry| entry.status == OrderStatus::Filled)
+                    })
+            });
+        self.resting = waiting;
+        ready.sort_by_key(|resting| {
+            !matches!(
+                resting.order.order_type,
+                OrderType::Stop { .. } | OrderType::StopLimit { .. }
+            )
+        });
+
+        //Entries whose leg filled on this bar, their other legs are in ready and not in the resting list cancel_legs goes through
+        let mut closed: Vec<String> = vec![];
+        for resting in ready {
+            if resting
+                .parent
+                .as_ref()
+                .is_some_and(|parent| closed.contains(parent))
+            {
+                self.cancel(&resting.id);
+                continue;
+            }
+            if self
+                .orders
+                .get(&resting.id)
+                .is_some_and(|record| record.status != OrderStatus::Open)
+            {
+                continue;
+            }
+            let new_day = today > resting.placed_on;
+            let filled_or = |price: Option<f64>, otherwise: Outcome| match price {
+                Some(price) => Outcome::Fill(price),
+                None => otherwise,
+            };
+            //Market orders placed near the close still fill at the next bar, like they did before order types
+            let market = resting.order.order_type == OrderType::Market;
+            let outcome = match resting.order.time_in_force {
+                TimeInForce::Day if new_day && !market => Outcome::Expire,
+                TimeInForce::Opg | TimeInForce::Cls if !new_day => Outcome::Wait,
+                TimeInForce::Opg => filled_or(
+                    bar_fill_price(&resting.order, open, open, open),
+                    Outcome::Expire,
+                ),
+                //The day's last close is only known once the next day's first bar shows up, so that's when it fills
+                TimeInForce::Cls => filled_or(
+                    previous_close
+                        .and_then(|close| bar_fill_price(&resting.order, close, close, close)),
+                    Outcome::Expire,
+                ),
+                TimeInForce::Ioc => filled_or(
+                    bar_fill_price(&resting.order, open, high, low),
+                    Outcome::Expire,
+                ),
+                TimeInForce::Day | TimeInForce::Gtc => filled_or(
+                    bar_fill_price(&resting.order, open, high, low),
+                    Outcome::Wait,
+                ),
+            };
+
+            match outcome {
+                Outcome::Fill(price) => {
+                    if let Err(e) = self.fill(
+                        cash,
+                        fill_model,
+                        &resting.id,
+                        &resting.order,
+                        price,
+                        bar.volume as f64,
+                    ) {
+                        warn!("Simulated order {} could not fill: {}", resting.id, e);
+                    }
+                    if let Some(parent) = &resting.parent {
+                        self.cancel_legs(parent);
+                        closed.push(parent.clone());
+                    }
+                }
+                Outcome::Wait => self.resting.push(resting),
+                Outcome::Expire => {
+                    info!("Simulated order {} expired", resting.id);
+                    self.cancel(&resting.id);
+                }
+            }
+        }
+    }
+
+    //Applies an order at the given bar price and returns what it filled at, slippage and commission come from the fill model
+    fn execute(
+        &mut self,
+        cash: &mut f64,
+        fill_model: &FillModel,
+        order: &OrderRequest,
+        bar_price: f64,
+        bar_volume: f64,
+    ) -> Result<f64> {
+        let quantity = order.quantity;
+        let price = fill_model.fill_price(order.side, bar_price, quantity, bar_volume);
+        //Slippage can't push a limit order past its limit
+        let price = match order.order_type {
+            OrderType::Limit { limit_price } | OrderType::StopLimit { limit_price, .. } => {
+                match order.side {
+                    OrderSide::Buy => price.min(limit_price),
+                    OrderSide::Sell => price.max(limit_price),
+                }
+            }
+            _ => price,
+        };
+        let total = price * quantity;
+        let commission = fill_model.commission(total);
+
+        match order.side {
+            OrderSide::Buy => {
+                if total + commission > *cash {
+                    return Err(Error::msg(format!(
+                        "Insufficient cash to buy {} shares of {}",
+                        order.quantity, &order.symbol
+                    )));
+                }
+                *cash -= total + commission;
+            }
+            //Selling more than is held goes short, the sale's cash is kept until it's bought back
+            OrderSide::Sell => *cash += total - commission,
+        }
+
+        let position = self
+            .positions
+            .entry(order.symbol.clone())
+            .or_insert(BrokerPosition {
+                symbol: order.symbol.clone(),
+                quantity: 0.0,
+                average_entry_price: 0.0,
+            });
+        let signed = match order.side {
+            OrderSide::Buy => quantity,
+            OrderSide::Sell => -quantity,
+        };
+        let held = position.quantity;
+        //The part of the order going against the position closes it, a sell closes a long and a buy covers a short
+        let closing = if held * signed < 0.0 {
+            quantity.min(held.abs())
+        } else {
+            0.0
+        };
+        let opening = quantity - closing;
+        let pnl = (price - position.average_entry_price) * closing * held.signum();
+
+        if opening > 0.0 {
+            let kept = held.abs() - closing;
+            position.average_entry_price =
+                (position.average_entry_price * kept + price * opening) / (kept + opening);
+        }
+        position.quantity += signed;
+        if position.quantity == 0.0 {
+            self.positions.remove(&order.symbol);
+        }
+        *self.realized.entry(order.symbol.clone()).or_insert(0.0) += pnl - commission;
+
+        self.fills.push(Fill {
+            time: self.now.unwrap_or_else(Utc::now),
+            symbol: order.symbol.clone(),
+            side: order.side,
+            quantity,
+            price,
+            commission,
+        });
+        Ok(price)
+    }
+}
+
+impl SimulatedBroker {
+    pub fn new(cash: Arc<RwLock<f64>>, fill_model: FillModel) -> Self {
+        Self {
+            cash,
+            fill_model,
+            book: Mutex::new(SimulatedBook::default()),
+        }
+    }
+
+    //Called by the backtest before a bar is handed to its monitor, orders resting on this symbol fill if the bar reached their price
+    pub fn mark(&self, bar: &Bar) {
+        let mut book = self.book.lock().unwrap();
+        let volume = bar.volume as f64;
+        book.now = Some(bar.timestamp);
+        book.charge_borrow(
+            &mut self.cash.write().unwrap(),
+            self.fill_model.borrow_rate,
+            &bar.symbol,
+            bar.timestamp,
+        );
+        let previous_close = book.last_prices.get(&bar.symbol).copied();
+        if let Some(close) = bar.close_price.to_f64() {
+            book.last_prices.insert(bar.symbol.clone(), close);
+        }
+        book.last_volumes.insert(bar.symbol.clone(), volume);
+
+        let mut cash = self.cash.write().unwrap();
+        book.work_resting(&mut cash, &self.fill_model, bar, previous_close);
+    }
+
+    pub fn fills(&self) -> Vec<Fill> {
+        self.book.lock().unwrap().fills.clone()
+    }
+
+    //Borrow fees charged on shorts of this symbol, already taken out of its realized profit
+    pub fn borrow_fees(&self, symbol: &str) -> f64 {
+        self.book
+            .lock()
+            .unwrap()
+            .borrow_fees
+            .get(symbol)
+            .copied()
+            .unwrap_or(0.0)
+    }
+
+    //Profit already locked in by closed positions in this symbol, less every commission and borrow fee paid on it
+    pub fn realized_pnl(&self, symbol: &str) -> f64 {
+        self.book
+            .lock()
+            .unwrap()
+            .realized
+            .get(symbol)
+            .copied()
+            .unwrap_or(0.0)
+    }
+
+    //Profit of the open position in this symbol at the last marked price, works for shorts since their quantity is negative
+    pub fn unrealized_pnl(&self, symbol: &str) -> f64 {
+        let book = self.book.lock().unwrap();
+        match (book.positions.get(symbol), book.last_prices.get(symbol)) {
+            (Some(position), Some(price)) => {
+                (price - position.average_entry_price) * position.quantity
+            }
+            _ => 0.0,
+        }
+    }
+
+    //Market value of the position in this symbol at the last marked price, negative for shorts
+    pub fn market_value(&self, symbol: &str) -> f64 {
+        let book = self.book.lock().unwrap();
+        match (book.positions.get(symbol), book.last_prices.get(symbol)) {
+            (Some(position), Some(price)) => price * position.quantity,
+            _ => 0.0,
+        }
+    }
+}
+
+impl Broker for SimulatedBroker {
+    fn submit_order(&self, order: OrderRequest) -> Result<SubmittedOrder> {
+        let mut book = self.book.lock().unwrap();
+        let order_id = book.new_order();
+        let placed_on = session_day(book.now.unwrap_or_else(Utc::now));
+
+        let mut legs = vec![];
+        if let Some(bracket) = order.bracket {
+            let exit = OrderRequest {
+                side: match order.side {
+                    OrderSide::Buy => OrderSide::Sell,
+                    OrderSide::Sell => OrderSide::Buy,
+                },
+                time_in_force: TimeInForce::Gtc,
+                extended_hours: false,
+                bracket: None,
+                ..order.clone()
+            };
+            let exits = [
+                bracket
+                    .stop_loss
+                    .map(|stop_price| OrderType::Stop { stop_price }),
+                bracket
+                    .take_profit
+                    .map(|limit_price| OrderType::Limit { limit_price }),
+            ];
+            for order_type in exits.into_iter().flatten() {
+                let leg_id = book.new_order();
+                book.resting.push(RestingOrder {
+                    id: leg_id.clone(),
+                    order: OrderRequest {
+                        order_type,
+                        ..exit.clone()
+                    },
+                    placed_on,
+                    parent: Some(order_id.clone()),
+                });
+                legs.push(leg_id);
+            }
+        }
+
+        //Filling at the current close, the order is tried at the last price right away and waits for later bars if that didn't reach it
+        let now = self.fill_model.fill_at == FillTiming::CurrentClose
+            && !matches!(order.time_in_force, TimeInForce::Opg | TimeInForce::Cls);
+        let price = now
+            .then(|| {
+                let price = order.reference_price;
+                bar_fill_price(&order, price, price, price)
+            })
+            .flatten();
+        match price {
+            Some(price) => {
+                let volume = book
+                    .last_volumes
+                    .get(&order.symbol)
+                    .copied()
+                    .unwrap_or_default();
+                let mut cash = self.cash.write().unwrap();
+                book.fill(
+                    &mut cash,
+                    &self.fill_model,
+                    &order_id,
+                    &order,
+                    price,
+                    volume,
+                )?;
+            }
+            None if now && order.time_in_force == TimeInForce::Ioc => book.cancel(&order_id),
+            None => book.resting.push(RestingOrder {
+                id: order_id.clone(),
+                order,
+                placed_on,
+                parent: None,
+            }),
+        }
+
+        Ok(SubmittedOrder { id: order_id, legs })
+    }
+
+    fn cancel_order(&self, order_id: &str) -> Result<()> {
+        let mut book = self.book.lock().unwrap();
+        let waiting = book.resting.len();
+        book.resting.retain(|resting| resting.id != order_id);
+        if book.resting.len() == waiting {
+            //Already filled or never existed
+            return Err(Error::msg(format!("No open order {}", order_id)));
+        }
+        book.cancel(order_id);
+        info!("Simulated order {} canceled", order_id);
+        Ok(())
+    }
+
+    fn order(&self, order_id: &str) -> Result<BrokerOrder> {
+        self.book
+            .lock()
+            .unwrap()
+            .orders
+            .get(order_id)
+            .cloned()
+            .ok_or_else(|| Error::msg(format!("No order {}", order_id)))
+    }
+
+    fn positions(&self) -> Result<Vec<BrokerPosition>> {
+        Ok(self
+            .book
+            .lock()
+            .unwrap()
+            .positions
+            .values()
+            .cloned()
+            .collect())
+    }
+
+    fn open_orders(&self) -> Result<Vec<OpenOrder>> {
+        Ok(self
+            .book
+            .lock()
+            .unwrap()
+            .resting
+            .iter()
+            .map(|resting| OpenOrder {
+                id: resting.id.clone(),
+                symbol: resting.order.symbol.clone(),
+                side: resting.order.side,
+            })
+            .collect())
+    }
+
+    fn cash(&self) -> Result<f64> {
+        Ok(*self.cash.read().unwrap())
+    }
+
+    fn equity(&self) -> Result<f64> {
+        let book = self.book.lock().unwrap();
+        let invested: f64 = book
+            .positions
+            .values()
+            .map(|position| {
+                book.last_prices
+                    .get(&position.symbol)
+                    .copied()
+                    .unwrap_or(position.average_entry_price)
+                    * position.quantity
+            })
+            .fold(0.0, |total, value| total + value);
+        Ok(*self.cash.read().unwrap() + invested)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use num_decimal::Num;
+
+    fn request(side: OrderSide, order_type: OrderType) -> OrderRequest {
+        OrderRequest {
+            symbol: "AAPL".to_string(),
+            side,
+            quantity: 1.0,
+            reference_price: 100.0,
+            order_type,
+            time_in_force: TimeInForce::Day,
+            extended_hours: false,
+            bracket: None,
+        }
+    }
+
+    fn bar(day: u32, minute: u32, open: f64, high: f64, low: f64, close: f64) -> Bar {
+        let price = |price: f64| Num::new((price * 100.0) as i64, 100);
+        Bar {
+            symbol: "AAPL".to_string(),
+            open_price: price(open),
+            high_price: price(high),
+            low_price: price(low),
+            close_price: price(close),
+            volume: 1000,
+            timestamp: Utc.with_ymd_and_hms(2022, 6, day, 15, minute, 0).unwrap(),
+        }
+    }
+
+    fn broker() -> SimulatedBroker {
+        let broker = SimulatedBroker::new(Arc::new(RwLock::new(10_000.0)), FillModel::default());
+        broker.mark(&bar(1, 0, 100.0, 100.0, 100.0, 100.0));
+        broker
+    }
+
+    #[test]
+    fn limits_fill_at_their_price_or_a_better_open() {
+        let buy = request(OrderSide::Buy, OrderType::Limit { limit_price: 100.0 });
+        assert_eq!(bar_fill_price(&buy, 102.0, 103.0, 99.0), Some(100.0));
+        //Opened below the limit, the open is better
+        assert_eq!(bar_fill_price(&buy, 98.0, 99.0, 97.0), Some(98.0));
+        assert_eq!(bar_fill_price(&buy, 102.0, 103.0, 100.01), None);
+
+        let sell = request(OrderSide::Sell, OrderType::Limit { limit_price: 100.0 });
+        assert_eq!(bar_fill_price(&sell, 98.0, 101.0, 97.0), Some(100.0));
+        assert_eq!(bar_fill_price(&sell, 103.0, 104.0, 102.0), Some(103.0));
+    }
+
+    #[test]
+    fn stops_that_gap_through_fill_at_the_open() {
+        let sell = request(OrderSide::Sell, OrderType::Stop { stop_price: 100.0 });
+        assert_eq!(bar_fill_price(&sell, 102.0, 103.0, 99.0), Some(100.0));
+        //Opened under the stop, it fills at the worse open
+        assert_eq!(bar_fill_price(&sell, 97.0, 98.0, 96.0), Some(97.0));
+        assert_eq!(bar_fill_price(&sell, 102.0, 103.0, 100.01), None);
+
+        let buy = request(OrderSide::Buy, OrderType::Stop { stop_price: 100.0 });
+        assert_eq!(bar_fill_price(&buy, 103.0, 104.0, 102.0), Some(103.0));
+        assert_eq!(bar_fill_price(&buy, 98.0, 100.5, 97.0), Some(100.0));
+    }
+
+    #[test]
+    fn stop_limits_skip_gaps_past_the_limit() {
+        let buy = request(
+            OrderSide::Buy,
+            OrderType::StopLimit {
+                stop_price: 100.0,
+                limit_price: 101.0,
+            },
+        );
+        assert_eq!(bar_fill_price(&buy, 99.0, 100.5, 98.0), Some(100.0));
+        assert_eq!(bar_fill_price(&buy, 100.5, 102.0, 100.2), Some(100.5));
+        assert_eq!(bar_fill_price(&buy, 103.0, 104.0, 102.0), None);
+    }
+
+    #[test]
+    fn market_orders_fill_at_the_open() {
+        let buy = request(OrderSide::Buy, OrderType::Market);
+        assert_eq!(bar_fill_price(&buy, 101.0, 102.0, 99.0), Some(101.0));
+    }
+
+    #[test]
+    fn resting_limit_fills_on_a_later_bar() {
+        let broker = broker();
+        let order = broker
+            .submit_order(request(
+                OrderSide::Buy,
+                OrderType::Limit { limit_price: 95.0 },
+            ))
+            .unwrap();
+        assert_eq!(broker.order(&order.id).unwrap().status, OrderStatus::Open);
+
+        broker.mark(&bar(1, 1, 97.0, 98.0, 96.0, 97.0));
+        assert_eq!(broker.order(&order.id).unwrap().status, OrderStatus::Open);
+
+        //Opened through the limit
+        broker.mark(&bar(1, 2, 94.0, 96.0, 93.0, 95.0));
+        let filled = broker.order(&order.id).unwrap();
+        assert_eq!(filled.status, OrderStatus::Filled);
+        assert_eq!(filled.filled_quantity, 1.0);
+        assert_eq!(filled.filled_avg_price, Some(94.0));
+        assert_eq!(broker.positions().unwrap()[0].quantity, 1.0);
+    }
+
+    #[test]
+    fn day_orders_expire_on_the_next_session() {
+        let broker = broker();
+        let order = broker
+            .submit_order(request(
+                OrderSide::Buy,
+                OrderType::Limit { limit_price: 90.0 },
+            ))
+            .unwrap();
+        broker.mark(&bar(2, 0, 85.0, 86.0, 84.0, 85.0));
+        assert_eq!(
+            broker.order(&order.id).unwrap().status,
+            OrderStatus::Canceled
+        );
+        assert!(broker.positions().unwrap().is_empty());
+    }
+
+    #[test]
+    fn bracket_stop_wins_when_a_bar_reaches_both_legs() {
+        let broker = broker();
+        let order = broker
+            .submit_order(OrderRequest {
+                bracket: Some(Bracket {
+                    take_profit: Some(110.0),
+                    stop_loss: Some(95.0),
+                }),
+                time_in_force: TimeInForce::Gtc,
+                ..request(OrderSide::Buy, OrderType::Market)
+            })
+            .unwrap();
+        assert_eq!(broker.order(&order.id).unwrap().status, OrderStatus::Filled);
+        let (stop, take_profit) = (&order.legs[0], &order.legs[1]);
+
+        broker.mark(&bar(1, 1, 100.0, 111.0, 94.0, 100.0));
+        let stop = broker.order(stop).unwrap();
+        assert_eq!(stop.status, OrderStatus::Filled);
+        assert_eq!(stop.filled_avg_price, Some(95.0));
+        assert_eq!(
+            broker.order(take_profit).unwrap().status,
+            OrderStatus::Canceled
+        );
+        assert!(broker.positions().unwrap().is_empty());
+    }
+}
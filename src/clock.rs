@@ -0,0 +1,24 @@
+use chrono::{DateTime, Utc};
+
+//Where the monitors get the current time from, backtests replay history so they cant use the wall clock
+#[derive(Clone, Copy)]
+pub enum Clock {
+    Wall,
+    Simulated(DateTime<Utc>),
+}
+
+impl Clock {
+    pub fn now(&self) -> DateTime<Utc> {
+        match self {
+            Clock::Wall => Utc::now(),
+            Clock::Simulated(time) => *time,
+        }
+    }
+
+    //Moves simulated time to the given time stamp, the wall clock moves on its own
+    pub fn advance_to(&mut self, time: DateTime<Utc>) {
+        if let Clock::Simulated(now) = self {
+            *now = time;
+        }
+    }
+}
@@ -1,11 +1,22 @@
 use crate::StockMonitor;
 
-use crate::alpaca_api::{APIThreadReq, APIThreadRes};
+use crate::backtesting::fill_model::FillModel;
+use crate::backtesting::monte_carlo::MonteCarloConfig;
+use crate::backtesting::optimizer::OptimizerConfig;
+use crate::bar_aggregator::Timeframe;
+use crate::broker::{Broker, TimeInForce};
+use crate::market_strategies::registry::{build_strategy, resolved_params};
+use crate::market_strategies::volume_filter::{VolumeFilter, VolumeFilterConfig};
+use crate::stock_processing::entry_order::EntryOrder;
+use crate::stock_processing::reconcile::ReconcilePolicy;
+use crate::stock_processing::sizing::Sizing;
+use crate::stock_processing::trailing_stop::{TrailingStop, TrailingStopConfig};
 //use crate::crypto_processing::crypto_monitor::CryptoMonitor;
-use crossbeam_channel::Sender;
+use anyhow::{Error, Result};
 use serde::Deserialize;
 use std::fs::File;
 use std::io::Read;
+use std::sync::Arc;
 use tracing::info;
 
 #[derive(Deserialize)]
@@ -16,12 +27,37 @@ pub struct BotConfig {
     pub stock_engine_config: EngineConfig,
     //pub crypto_engine_config: EngineConfig,
     pub testing_mode: bool,
+    //Only needed when running the bot with the "optimize" argument
+    pub optimizer: Option<OptimizerConfig>,
 }
 
 #[derive(Deserialize)]
 pub struct EngineConfig {
     pub backtest_money: f64,
     pub threads: usize,
+    //How many hours the time stamps in backtest_data are from UTC
+    #[serde(default = "default_backtest_utc_offset")]
+    pub backtest_utc_offset: i32,
+    //Where the backtest writes its report files
+    #[serde(default = "default_report_dir")]
+    pub report_dir: String,
+    //Commission, slippage and fill timing for backtests, fills are free and at the close when left out
+    #[serde(default)]
+    pub fill_model: FillModel,
+    //Resamples the backtest's trades after it finishes, skipped when left out
+    pub monte_carlo: Option<MonteCarloConfig>,
+    //What happens at startup when the DB and the broker disagree, not used in backtests
+    #[serde(default)]
+    pub reconcile_policy: ReconcilePolicy,
+}
+
+//The backtest data is in New York time during daylight saving
+fn default_backtest_utc_offset() -> i32 {
+    -4
+}
+
+fn default_report_dir() -> String {
+    "./backtest_reports".to_string()
 }
 
 /*
@@ -39,7 +75,7 @@ impl Crypto {
         self,
         backtest_mode: bool,
         api_tx: Sender<(APIThreadReq, Sender<APIThreadRes>)>,
-    ) -> CryptoMonitor {
+    ) -> Result<CryptoMonitor> {
         CryptoMonitor::new(
             self.symbol,
             api_tx,
@@ -53,34 +89,169 @@ impl Crypto {
 }
 */
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub struct Stock {
     symbol: String,
     strategy: String,
+    //Settings for the strategy, anything left out uses the strategy's default
+    strategy_params: Option<toml::Value>,
+    //Ignores the strategy's signals on low volume bars
+    volume_filter: Option<VolumeFilterConfig>,
+    //Closes the position when the price gives back a percent or some ATRs from its best since entry
+    trailing_stop: Option<TrailingStopConfig>,
+    //Bar size the strategy runs on, built from the 1 minute bars
+    #[serde(default)]
+    timeframe: Timeframe,
     emergency_limit: f64,
     upper_limit: Option<f64>,
     intensity: u32,
+    //Sizes positions by dollars or percent of equity in fractional shares instead of intensity whole shares
+    #[serde(default)]
+    sizing: Sizing,
+    //Percent of the cash a buy can lose at the strategy's stop, for strategies that give one
+    risk_per_trade: Option<f64>,
+    //Order that opens positions, market unless set
+    #[serde(default)]
+    entry_order: EntryOrder,
+    #[serde(default)]
+    time_in_force: TimeInForce,
+    //Lets entries fill before and after the regular session, alpaca wants a day limit order for it
+    #[serde(default)]
+    extended_hours: bool,
+    //Has the broker hold the stop and take profit orders so the position is covered while the bot is down
+    #[serde(default)]
+    bracket_orders: bool,
+    //Lets sell signals open a short position when nothing is held, the account has to be allowed to short
+    #[serde(default)]
+    allow_short: bool,
 }
 impl Stock {
     pub fn get_symbol(&self) -> String {
         self.symbol.clone()
     }
 
+    //The optimizer's settings as a stock, so its monitors are checked and built the same way as the config's
+    pub fn from_optimizer(config: &OptimizerConfig) -> Self {
+        Self {
+            symbol: "optimizer".to_string(),
+            strategy: config.strategy.clone(),
+            strategy_params: None,
+            volume_filter: config.volume_filter.clone(),
+            trailing_stop: config.trailing_stop,
+            timeframe: config.timeframe,
+            emergency_limit: config.emergency_limit,
+            upper_limit: config.upper_limit,
+            intensity: config.intensity,
+            sizing: config.sizing,
+            risk_per_trade: config.risk_per_trade,
+            entry_order: config.entry_order,
+            time_in_force: config.time_in_force,
+            extended_hours: config.extended_hours,
+            bracket_orders: config.bracket_orders,
+            allow_short: config.allow_short,
+        }
+    }
+
+    //Same stock on another symbol with other strategy_params, how the optimizer tries its combinations
+    pub fn with(&self, symbol: &str, strategy_params: toml::Value) -> Self {
+        Self {
+            symbol: symbol.to_string(),
+            strategy_params: Some(strategy_params),
+            ..self.clone()
+        }
+    }
+
+    //Makes sure the strategy exists and its strategy_params fit it
+    pub fn validate(&self) -> Result<()> {
+        build_strategy(&self.strategy, self.strategy_params.as_ref())
+            .map(|_| ())
+            .and_then(|_| match &self.volume_filter {
+                Some(filter) => VolumeFilter::new(filter.clone()).map(|_| ()),
+                None => Ok(()),
+            })
+            .and_then(|_| match self.risk_per_trade {
+                Some(risk) if risk <= 0.0 => Err(Error::msg("risk_per_trade has to be above 0")),
+                _ => Ok(()),
+            })
+            .and_then(|_| match self.trailing_stop {
+                Some(config) => TrailingStop::new(config).map(|_| ()),
+                None => Ok(()),
+            })
+            .and_then(|_| self.sizing.validate())
+            .and_then(|_| self.entry_order.validate())
+            .and_then(|_| self.check_order_rules())
+            .and_then(|_| {
+                if self.bracket_orders && self.sizing.fractional() {
+                    Err(Error::msg(
+                        "bracket_orders needs whole shares, alpaca doesn't take brackets on fractional orders",
+                    ))
+                } else {
+                    Ok(())
+                }
+            })
+            .map_err(|e| Error::msg(format!("[{}] {}", &self.symbol, e)))
+    }
+
+    //What alpaca takes together
+    fn check_order_rules(&self) -> Result<()> {
+        if self.extended_hours
+            && !(self.entry_order.is_limit() && self.time_in_force == TimeInForce::Day)
+        {
+            return Err(Error::msg(
+                "extended_hours needs a limit entry_order and a day time_in_force",
+            ));
+        }
+        if self.sizing.fractional() && self.time_in_force != TimeInForce::Day {
+            return Err(Error::msg(
+                "Fractional sizing only works with a day time_in_force",
+            ));
+        }
+        if self.bracket_orders
+            && (self.extended_hours
+                || !matches!(self.time_in_force, TimeInForce::Day | TimeInForce::Gtc))
+        {
+            return Err(Error::msg(
+                "bracket_orders are sent good until canceled, they can't be extended_hours or have a time_in_force other than day or gtc",
+            ));
+        }
+        Ok(())
+    }
+
     //Convert a stock in the config into a monitor
     pub fn convert(
         self,
         backtest_mode: bool,
-        api_tx: Sender<(APIThreadReq, Sender<APIThreadRes>)>,
-    ) -> StockMonitor {
-        StockMonitor::new(
+        broker: Arc<dyn Broker + Send + Sync>,
+    ) -> Result<StockMonitor> {
+        let strategy = build_strategy(&self.strategy, self.strategy_params.as_ref())
+            .map_err(|e| Error::msg(format!("[{}] {}", &self.symbol, e)))?;
+
+        let params = resolved_params(&self.strategy, self.strategy_params.as_ref())?;
+        let mut monitor = StockMonitor::new(
             self.symbol,
-            api_tx,
+            broker,
             backtest_mode,
-            self.strategy,
+            strategy,
             self.emergency_limit,
             self.upper_limit,
             self.intensity,
-        )
+        );
+        if let Some(filter) = self.volume_filter {
+            monitor.set_volume_filter(VolumeFilter::new(filter)?);
+        }
+        if let Some(config) = self.trailing_stop {
+            monitor.set_trailing_stop(TrailingStop::new(config)?);
+        }
+        monitor.set_strategy_params(params);
+        monitor.set_timeframe(self.timeframe);
+        if let Some(risk) = self.risk_per_trade {
+            monitor.set_risk_per_trade(risk);
+        }
+        monitor.set_sizing(self.sizing);
+        monitor.set_entry_order(self.entry_order, self.time_in_force, self.extended_hours);
+        monitor.set_bracket_orders(self.bracket_orders);
+        monitor.set_allow_short(self.allow_short);
+        Ok(monitor)
     }
 }
 
@@ -92,12 +263,18 @@ pub struct ApiKeys {
 }
 
 impl BotConfig {
-    pub fn load_config() -> Self {
-        let mut file = File::open("./Config.toml").unwrap();
+    pub fn load_config() -> Result<Self> {
+        let mut file = File::open("./Config.toml")?;
         let mut buffer: String = String::new();
-        file.read_to_string(&mut buffer).unwrap();
+        file.read_to_string(&mut buffer)?;
+
+        let config: Self = toml::from_str(&buffer)?;
+        //Check every stock's strategy now instead of finding out when its monitor is made
+        for stock in &config.stocks {
+            stock.validate()?;
+        }
 
         info!("Loaded config");
-        toml::from_str(&buffer).unwrap()
+        Ok(config)
     }
 }
@@ -1,9 +1,8 @@
 use crate::alpaca_api::{APIThreadReq, APIThreadRes};
 use crate::data_input::{CryptoDataInput, StockDataInput};
 use crate::json_structs::{CryptoMarketData, MarketData};
-use crate::market_strategies::{
-    CryptoStrategy, SingleMovingAverage, StrategyOutput, TwoMovingAverages,
-};
+use crate::market_strategies::registry::{build_strategy, find_strategy, BoxedStrategy};
+use crate::market_strategies::StrategyOutput;
 use anyhow::Result;
 use apca::api::v2::order::Amount;
 use chrono::{Datelike, Utc};
@@ -16,7 +15,7 @@ use std::sync::RwLock;
 use tracing::{error, info, warn};
 
 pub struct CryptoMonitor {
-    crypto_strategy: Box<dyn CryptoStrategy + Send + Sync + 'static>,
+    crypto_strategy: BoxedStrategy,
     backtest_mode: bool,
     api_tx: Sender<(APIThreadReq, Sender<APIThreadRes>)>,
     input: CryptoDataInput,
@@ -47,15 +46,9 @@ impl CryptoMonitor {
         emergency_margin_limit: f64,
         upper_limit: Option<f64>,
         buy_limit: u32,
-    ) -> Self {
-        let strat: Box<dyn CryptoStrategy + Send + Sync> = match &*strategy {
-            "Single Moving Average" => Box::new(SingleMovingAverage::new()),
-            "Two Moving Averages" => Box::new(TwoMovingAverages::new()),
-            _ => {
-                error!("[{}] Unknown strategy", &symbol);
-                panic!("Unknown strategy selected")
-            }
-        };
+    ) -> Result<Self> {
+        //Same registry the stocks use, an unknown name is an error instead of a panic
+        let strat = build_strategy(&strategy, None)?;
 
         if backtest_mode {
             info!("[{}] Starting in backtest mode", &symbol);
@@ -76,7 +69,7 @@ impl CryptoMonitor {
             &id
         ));
 
-        Self {
+        Ok(Self {
             crypto_strategy: strat,
             backtest_mode,
             api_tx,
@@ -88,7 +81,7 @@ impl CryptoMonitor {
             upper_limit,
             buy_limit,
             how_much_bought: Num::default(),
-        }
+        })
     }
 
     pub fn run(&mut self, assets: Arc<RwLock<f64>>) -> Result<()> {
@@ -110,14 +103,14 @@ impl CryptoMonitor {
             if percentage <= self.emergency_margin_limit {
                 self.api_tx
                     .send((
-                        APIThreadReq::ApiSellCrypto {
+                        APIThreadReq::SellCrypto {
                             symbol: self.symbol.clone(),
                             quantity: self.how_much_bought.clone(),
                         },
                         res_tx,
                     ))
                     .unwrap();
-                if let Ok(APIThreadRes::ApiProcessed) = res_rx.recv() {
+                if let Ok(APIThreadRes::Processed) = res_rx.recv() {
                     self.bought_crypto = false;
                     warn!(
                         "[{}]: Emergency margin triggered!!! Sold at : {}",
@@ -132,14 +125,14 @@ impl CryptoMonitor {
             if self.upper_limit.is_some() && percentage >= self.upper_limit.unwrap() {
                 self.api_tx
                     .send((
-                        APIThreadReq::ApiSellCrypto {
+                        APIThreadReq::SellCrypto {
                             symbol: self.symbol.clone(),
                             quantity: self.how_much_bought.clone(),
                         },
                         res_tx,
                     ))
                     .unwrap();
-                if let Ok(APIThreadRes::ApiProcessed) = res_rx.recv() {
+                if let Ok(APIThreadRes::Processed) = res_rx.recv() {
                     self.bought_crypto = false;
                     warn!(
                         "[{}]: Upper bound triggered, Sold at : {}",
@@ -176,14 +169,14 @@ impl CryptoMonitor {
                     //if so, buy
                     self.api_tx
                         .send((
-                            APIThreadReq::ApiBuyCrypto {
+                            APIThreadReq::BuyCrypto {
                                 symbol: self.symbol.clone(),
                                 quantity: Num::new(total_money_to_use, data.usd as u32),
                             },
                             res_tx,
                         ))
                         .unwrap();
-                    if let Ok(APIThreadRes::ApiProcessed) = res_rx.recv() {
+                    if let Ok(APIThreadRes::Processed) = res_rx.recv() {
                         self.bought_at = data.usd;
                         self.bought_crypto = true;
                         //Use num-decimal crate to turn our buy money into a crypto fraction
@@ -212,14 +205,14 @@ impl CryptoMonitor {
                 //Sell
                 self.api_tx
                     .send((
-                        APIThreadReq::ApiSellCrypto {
+                        APIThreadReq::SellCrypto {
                             symbol: self.symbol.clone(),
                             quantity: self.how_much_bought.clone(),
                         },
                         res_tx,
                     ))
                     .unwrap();
-                if let Ok(APIThreadRes::ApiProcessed) = res_rx.recv() {
+                if let Ok(APIThreadRes::Processed) = res_rx.recv() {
                     self.bought_crypto = false;
                     info!(
                         "[{}]: sold {} dollars worth of crypto at {} per 1.0 fraction",
@@ -349,19 +342,15 @@ impl CryptoMonitor {
     pub fn set_state(&mut self, simple_mon: SimplifiedCryptoDBMonitor) {
         self.bought_crypto = simple_mon.bought_crypto;
         self.bought_at = simple_mon.buy_price;
-        let strat: Box<dyn CryptoStrategy + Send + Sync> = match &*simple_mon.strat_name {
-            "Single Moving Average" => Box::new(
-                bincode::deserialize::<SingleMovingAverage>(&simple_mon.strat_bytes).unwrap(),
-            ),
-            "Two Moving Averages" => Box::new(
-                bincode::deserialize::<TwoMovingAverages>(&simple_mon.strat_bytes).unwrap(),
+        match find_strategy(&simple_mon.strat_name)
+            .and_then(|entry| Ok(entry.restore(&simple_mon.strat_bytes)?))
+        {
+            Ok(strat) => self.crypto_strategy = strat,
+            Err(e) => warn!(
+                "[{}] Could not load strategy state from DB, starting it fresh: {}",
+                &self.symbol, e
             ),
-            _ => {
-                error!("[{}] Unknown strategy", &self.symbol);
-                panic!("Unknown strategy selected")
-            }
-        };
-        self.crypto_strategy = strat;
+        }
         self.how_much_bought = simple_mon.how_much;
     }
 }
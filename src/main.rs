@@ -1,38 +1,74 @@
 extern crate core;
 
 mod alpaca_api;
+mod backtesting;
+mod bar_aggregator;
+mod broker;
+mod clock;
 mod config;
 //mod crypto_processing;
 mod market_strategies;
 mod stock_processing;
 
 use crate::config::BotConfig;
-use anyhow::Result;
+use anyhow::{Error, Result};
 use apca::ApiInfo;
 use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
 use std::{panic, process};
-use threadpool::ThreadPool;
 //use std::thread::spawn;
 
 use crate::alpaca_api::alpaca_api_thread;
+use crate::broker::{AlpacaBroker, Broker, SimulatedBroker};
 //use crate::crypto_processing::crypto_monitor::{CryptoMonitor, SimplifiedCryptoDBMonitor};
+use crate::stock_processing::reconcile::reconcile;
 use crate::stock_processing::stock_monitor::{SimplifiedDBMonitor, StockMonitor};
 use tracing::{info, Level};
-
-fn logger_init() {
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::prelude::*;
+
+fn logger_init(optimizing: bool) {
+    //The optimizer runs the monitors thousands of times, only their errors are worth seeing
+    let targets = if optimizing {
+        Targets::new()
+            .with_default(Level::INFO)
+            .with_target("trade_bot::stock_processing", Level::ERROR)
+            .with_target("trade_bot::backtesting::engine", Level::ERROR)
+    } else {
+        Targets::new().with_default(Level::INFO)
+    };
     tracing_subscriber::fmt()
         .compact()
         .with_thread_names(true)
         .with_max_level(Level::INFO)
+        .finish()
+        .with(targets)
         .init();
     info!("Logger initialized")
 }
 
 fn main() -> Result<()> {
-    logger_init();
-
-    let config: BotConfig = BotConfig::load_config();
+    //Running "trade-bot optimize" sweeps the strategy params in the [optimizer] section over backtest_data instead of trading
+    //"trade-bot walk-forward" does the same over rolling windows and tests each winner on the days after it
+    //"trade-bot strategies" lists the strategies and their strategy_params without needing a config
+    let mode = std::env::args().nth(1);
+    if mode.as_deref() == Some("strategies") {
+        return market_strategies::registry::print_strategies();
+    }
+    let optimizing = matches!(mode.as_deref(), Some("optimize" | "walk-forward"));
+    logger_init(optimizing);
+
+    let config: BotConfig = BotConfig::load_config()?;
+    if optimizing {
+        let optimizer_config = config
+            .optimizer
+            .ok_or_else(|| Error::msg("No [optimizer] section in Config.toml"))?;
+        return if mode.as_deref() == Some("walk-forward") {
+            backtesting::walk_forward::run(&optimizer_config, &config.stock_engine_config)
+        } else {
+            backtesting::optimizer::run(&optimizer_config, &config.stock_engine_config)
+        };
+    }
     info!("Loading state DB");
 
     //Loads the stock monitors from config, using DB to set their last state (if they bought stocks and such)
@@ -61,32 +97,59 @@ fn main() -> Result<()> {
         active_stocks,
     );
 
-    //Load stocks from config and load any of their past states from the DB
+    //Backtests trade against an in process broker that shares the allocated currency, everything else goes to alpaca
     let backtesting = config.testing_mode;
+    let simulated_broker = Arc::new(SimulatedBroker::new(
+        allowed_currency.clone(),
+        config.stock_engine_config.fill_model.clone(),
+    ));
+    let broker: Arc<dyn Broker + Send + Sync> = if backtesting {
+        simulated_broker.clone()
+    } else {
+        Arc::new(AlpacaBroker::new(tx))
+    };
+
+    //Load stocks from config and load any of their past states from the DB
     let mut stock_monitors_safe: HashMap<String, Arc<RwLock<StockMonitor>>> = HashMap::default();
     for stock in config.stocks {
         let name = stock.get_symbol();
-        let mut stock_monitor = stock.convert(backtesting, tx.clone());
+        let mut stock_monitor = stock.convert(backtesting, broker.clone())?;
 
         //If the stock's name is in the DB load the old state
-        if let Ok(Some(data)) = stock_state_db.get(stock_monitor.symbol.as_bytes()) {
+        //Backtests start flat, the DB holds live positions and alpaca order IDs the simulated broker knows nothing about
+        let saved = if backtesting {
+            Ok(None)
+        } else {
+            stock_state_db.get(stock_monitor.symbol.as_bytes())
+        };
+        if let Ok(Some(data)) = saved {
             info!(
                 "Loading past stock state for symbol: {}",
                 &stock_monitor.symbol
             );
             let raw_bytes = data.to_vec();
 
-            let simplified_data: SimplifiedDBMonitor = bincode::deserialize(&raw_bytes).unwrap();
+            let simplified_data = SimplifiedDBMonitor::from_bytes(&raw_bytes)?;
 
             stock_monitor.set_state(simplified_data);
         }
 
         stock_monitors_safe.insert(name, Arc::new(RwLock::new(stock_monitor)));
     }
+
+    //The DB is only what the bot last saw, check it against the account before trading on it
+    if !backtesting {
+        reconcile(
+            &stock_monitors_safe,
+            &broker,
+            &stock_state_db,
+            config.stock_engine_config.reconcile_policy,
+        )?;
+    }
     /*
     let mut crypto_monitors_safe: Vec<Arc<RwLock<CryptoMonitor>>> = vec![];
     for crypto in config.crypto {
-        let mut crypto_monitor = crypto.convert(backtesting, tx.clone());
+        let mut crypto_monitor = crypto.convert(backtesting, tx.clone())?;
 
         if let Ok(Some(data)) = stock_state_db.get(crypto_monitor.symbol.as_bytes()) {
             info!(
@@ -130,8 +193,6 @@ fn main() -> Result<()> {
         process::exit(-1);
     }));
 
-    let pool = ThreadPool::new(config.stock_engine_config.threads);
-
     //Begin running stock loop
     stock_processing::stock_ticker_loop::start_loop(
         backtesting,
@@ -139,7 +200,8 @@ fn main() -> Result<()> {
         allowed_currency,
         rx,
         stock_state_db,
-        pool,
+        simulated_broker,
+        config.stock_engine_config,
     );
 
     Ok(())
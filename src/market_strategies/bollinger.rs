@@ -0,0 +1,251 @@
+use crate::market_strategies::registry::{ParamSpec, RegisteredStrategy};
+use crate::market_strategies::{StockStrategy, StrategyOutput};
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use ta::indicators::BollingerBands as Bands;
+use ta::Next;
+
+const DEFAULT_SQUEEZE_LOOKBACK: usize = 120;
+const DEFAULT_SQUEEZE_TOLERANCE: f64 = 0.1;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum BollingerMode {
+    //Buy at the lower band, sell once price is back at the middle band
+    MeanReversion,
+    //Buy a close above the upper band after a squeeze, sell when price falls back under the middle band
+    Breakout,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct BollingerParams {
+    pub period: usize,
+    //How many standard deviations the bands are from the middle
+    pub multiplier: f64,
+    pub mode: BollingerMode,
+    //Breakout only, how many bars of band width the squeeze is measured against
+    pub squeeze_lookback: Option<usize>,
+    //Breakout only, the bands count as squeezed when they're within this fraction of the narrowest width in the lookback
+    pub squeeze_tolerance: Option<f64>,
+}
+
+impl Default for BollingerParams {
+    fn default() -> Self {
+        Self {
+            period: 20,
+            multiplier: 2.0,
+            mode: BollingerMode::MeanReversion,
+            squeeze_lookback: None,
+            squeeze_tolerance: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BollingerBands {
+    params: BollingerParams,
+    bands: Bands,
+    //Band width relative to the middle band for the last squeeze_lookback bars
+    widths: VecDeque<f64>,
+    //Set when a squeeze is seen, a breakout uses it up
+    squeezed: bool,
+    bars_seen: usize,
+}
+
+impl BollingerBands {
+    fn squeeze_lookback(&self) -> usize {
+        self.params
+            .squeeze_lookback
+            .unwrap_or(DEFAULT_SQUEEZE_LOOKBACK)
+    }
+
+    //True when the bands are about as tight as they've been over the whole lookback
+    fn is_squeeze(&self, width: f64) -> bool {
+        if self.widths.len() < self.squeeze_lookback() {
+            return false;
+        }
+        let narrowest = self.widths.iter().copied().fold(f64::MAX, f64::min);
+        let tolerance = self
+            .params
+            .squeeze_tolerance
+            .unwrap_or(DEFAULT_SQUEEZE_TOLERANCE);
+        width <= narrowest * (1.0 + tolerance)
+    }
+}
+
+impl RegisteredStrategy for BollingerBands {
+    const NAME: &'static str = "Bollinger Bands";
+    const DESCRIPTION: &'static str =
+        "Mean reversion off the lower band, or breakouts above the upper band after a squeeze";
+    const PARAMS: &'static [ParamSpec] = &[
+        ParamSpec {
+            name: "period",
+            description: "Bars in the middle band's SMA and the standard deviation",
+        },
+        ParamSpec {
+            name: "multiplier",
+            description: "Standard deviations between the middle band and the outer bands",
+        },
+        ParamSpec {
+            name: "mode",
+            description: "\"mean_reversion\" or \"breakout\"",
+        },
+        ParamSpec {
+            name: "squeeze_lookback",
+            description: "Breakout only, bars of band width to look for the squeeze in (default 120)",
+        },
+        ParamSpec {
+            name: "squeeze_tolerance",
+            description: "Breakout only, how close (as a fraction) the width has to be to the narrowest in the lookback to be a squeeze (default 0.1)",
+        },
+    ];
+    type Params = BollingerParams;
+
+    fn from_params(params: BollingerParams) -> anyhow::Result<Self> {
+        if params.squeeze_lookback == Some(0) || params.squeeze_tolerance.unwrap_or(0.0) < 0.0 {
+            return Err(Error::msg(format!(
+                "Bad strategy_params for {}: squeeze_lookback has to be above 0 and squeeze_tolerance can't be negative",
+                Self::NAME
+            )));
+        }
+        let bands = Bands::new(params.period, params.multiplier).map_err(|_| {
+            Error::msg(format!(
+                "Bad strategy_params for {}: period and multiplier have to be above 0",
+                Self::NAME
+            ))
+        })?;
+
+        Ok(Self {
+            params,
+            bands,
+            widths: VecDeque::new(),
+            squeezed: false,
+            bars_seen: 0,
+        })
+    }
+}
+
+impl StockStrategy for BollingerBands {
+    fn save_state(&self) -> (Vec<u8>, String) {
+        (bincode::serialize(&self).unwrap(), Self::NAME.to_string())
+    }
+
+    fn run_backtest(
+        &mut self,
+        _open: f64,
+        close: f64,
+        _high: f64,
+        _low: f64,
+        _volume: f64,
+    ) -> StrategyOutput {
+        let bands = self.bands.next(close);
+        self.bars_seen += 1;
+        if self.bars_seen < self.params.period {
+            return StrategyOutput::Hold;
+        }
+
+        let width = if bands.average != 0.0 {
+            (bands.upper - bands.lower) / bands.average
+        } else {
+            0.0
+        };
+        if self.is_squeeze(width) {
+            self.squeezed = true;
+        }
+        self.widths.push_back(width);
+        if self.widths.len() > self.squeeze_lookback() {
+            self.widths.pop_front();
+        }
+
+        match self.params.mode {
+            BollingerMode::MeanReversion => {
+                if close <= bands.lower {
+                    return StrategyOutput::Buy;
+                }
+                if close >= bands.average {
+                    return StrategyOutput::Sell;
+                }
+            }
+            BollingerMode::Breakout => {
+                if close > bands.upper && self.squeezed {
+                    self.squeezed = false;
+                    return StrategyOutput::Buy;
+                }
+                if close < bands.average {
+                    return StrategyOutput::Sell;
+                }
+            }
+        }
+        StrategyOutput::Hold
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bollinger(mode: BollingerMode) -> BollingerBands {
+        BollingerBands::from_params(BollingerParams {
+            period: 5,
+            multiplier: 1.0,
+            mode,
+            squeeze_lookback: Some(5),
+            squeeze_tolerance: None,
+        })
+        .unwrap()
+    }
+
+    fn run(strategy: &mut BollingerBands, prices: &[f64]) -> Vec<StrategyOutput> {
+        prices
+            .iter()
+            .map(|&close| strategy.run_backtest(close, close, close, close, 0.0))
+            .collect()
+    }
+
+    #[test]
+    fn mean_reversion_buys_the_lower_band_and_sells_the_middle() {
+        let outputs = run(
+            &mut bollinger(BollingerMode::MeanReversion),
+            &[100.0, 101.0, 100.0, 101.0, 100.0, 95.0, 99.5],
+        );
+        assert!(outputs[..4]
+            .iter()
+            .all(|output| *output == StrategyOutput::Hold));
+        //Under the middle but inside the bands
+        assert_eq!(outputs[4], StrategyOutput::Hold);
+        assert_eq!(outputs[5], StrategyOutput::Buy);
+        assert_eq!(outputs[6], StrategyOutput::Sell);
+    }
+
+    #[test]
+    fn breakout_buys_a_close_above_the_upper_band_after_a_squeeze() {
+        let mut prices = vec![100.0, 104.0, 98.0, 105.0, 97.0, 104.0, 98.0, 105.0];
+        prices.extend([100.0, 100.2, 100.0, 100.2, 100.0, 100.2, 100.0]);
+        prices.extend([103.0, 104.0, 99.0]);
+        let outputs = run(&mut bollinger(BollingerMode::Breakout), &prices);
+        assert_eq!(outputs[15], StrategyOutput::Buy);
+        //The breakout uses the squeeze up
+        assert_ne!(outputs[16], StrategyOutput::Buy);
+        assert_eq!(outputs[17], StrategyOutput::Sell);
+        assert_eq!(
+            outputs
+                .iter()
+                .filter(|output| **output == StrategyOutput::Buy)
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn breakout_ignores_the_upper_band_while_the_bands_are_widening() {
+        let prices = [
+            100.0, 101.0, 99.0, 102.0, 98.0, 103.0, 97.0, 104.0, 96.0, 105.0, 95.0, 112.0,
+        ];
+        let mut strategy = bollinger(BollingerMode::Breakout);
+        let outputs = run(&mut strategy, &prices);
+        assert!(!strategy.squeezed);
+        assert!(outputs.iter().all(|output| *output != StrategyOutput::Buy));
+    }
+}
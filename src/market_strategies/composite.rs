@@ -0,0 +1,527 @@
+use crate::bar_aggregator::Timeframe;
+use crate::market_strategies::registry::{
+    build_strategy, find_strategy, BoxedStrategy, ParamSpec, RegisteredStrategy,
+};
+use crate::market_strategies::{Signal, StockStrategy, StrategyOutput};
+use anyhow::Error;
+use apca::data::v2::stream::Bar;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+const DEFAULT_THRESHOLD: f64 = 0.5;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum CompositeMode {
+    //Buy or sell when more than half the children agree
+    Majority,
+    //Buy or sell only when every child agrees, an AND of all of them
+    Unanimous,
+    //Every child votes with its weight, buy or sell once the score gets to threshold
+    Weighted,
+    //Act on any child's buy or sell on the bar it happens, as long as no other child says the opposite, an OR of all of them
+    Any,
+    //The first child is a filter, entries from the rest only go through while the filter's last signal was the same way
+    //A buy with nothing held (or a sell that would open a short) is an entry, exits always go through
+    Filter,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ChildParams {
+    //Same as a stock's strategy and strategy_params, leaving out strategy_params uses the child's defaults
+    pub strategy: String,
+    pub strategy_params: Option<toml::Value>,
+    //Weighted only, defaults to 1
+    pub weight: Option<f64>,
+    //Runs the child on bars of this timeframe instead of the stock's, like a daily trend filter in front of a 5m trigger
+    pub timeframe: Option<Timeframe>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CompositeParams {
+    pub mode: CompositeMode,
+    pub children: Vec<ChildParams>,
+    //Weighted only, the fraction of the total weight the score has to reach
+    pub threshold: Option<f64>,
+}
+
+impl Default for CompositeParams {
+    //Trend filter plus entry trigger
+    fn default() -> Self {
+        let child = |strategy: &str| ChildParams {
+            strategy: strategy.to_string(),
+            strategy_params: None,
+            weight: None,
+            timeframe: None,
+        };
+        Self {
+            mode: CompositeMode::Filter,
+            children: vec![
+                child("Two Moving Averages"),
+                child("Relative Strength Index"),
+            ],
+            threshold: None,
+        }
+    }
+}
+
+struct Child {
+    weight: f64,
+    strategy: BoxedStrategy,
+    //None runs on the stock's own bars
+    timeframe: Option<Timeframe>,
+    //The child's last buy or sell, it votes with this until it changes its mind so event based strategies still count between signals
+    stance: Signal,
+    //What a child on its own timeframe said since the last bar of the stock, counts as its signal on that bar
+    pending: Signal,
+}
+
+//Runs every child on every bar so they all stay warmed up, then combines what they say according to the mode
+pub struct Composite {
+    mode: CompositeMode,
+    threshold: f64,
+    children: Vec<Child>,
+    //What the monitor holds, negative when short, tells the filter mode's entries from exits
+    position: f64,
+}
+
+//What goes in the DB, each child is saved with its own save_state and brought back through the registry
+#[derive(Serialize, Deserialize)]
+struct SavedChild {
+    name: String,
+    bytes: Vec<u8>,
+    weight: f64,
+    timeframe: Option<Timeframe>,
+    stance: Signal,
+    pending: Signal,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedComposite {
+    mode: CompositeMode,
+    threshold: f64,
+    children: Vec<SavedChild>,
+}
+
+impl Serialize for Composite {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        SavedComposite {
+            mode: self.mode,
+            threshold: self.threshold,
+            children: self
+                .children
+                .iter()
+                .map(|child| {
+                    let (bytes, name) = child.strategy.save_state();
+                    SavedChild {
+                        name,
+                        bytes,
+                        weight: child.weight,
+                        timeframe: child.timeframe,
+                        stance: child.stance,
+                        pending: child.pending,
+                    }
+                })
+                .collect(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Composite {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let saved = SavedComposite::deserialize(deserializer)?;
+        let children = saved
+            .children
+            .into_iter()
+            .map(|child| {
+                let strategy = find_strategy(&child.name)
+                    .map_err(de::Error::custom)?
+                    .restore(&child.bytes)
+                    .map_err(de::Error::custom)?;
+                Ok(Child {
+                    weight: child.weight,
+                    strategy,
+                    timeframe: child.timeframe,
+                    stance: child.stance,
+                    pending: child.pending,
+                })
+            })
+            .collect::<Result<_, D::Error>>()?;
+        Ok(Self {
+            mode: saved.mode,
+            threshold: saved.threshold,
+            children,
+            position: 0.0,
+        })
+    }
+}
+
+impl Composite {
+    fn stances(&self, side: StrategyOutput) -> usize {
+        self.children
+            .iter()
+            .filter(|child| child.stance.direction == side)
+            .count()
+    }
+
+    //One signal out of the ones going the same way, confidence is their average and the stop, target and size come from the first child that sets them
+    fn merge<'a>(direction: StrategyOutput, signals: impl Iterator<Item = &'a Signal>) -> Signal {
+        let agreeing: Vec<&Signal> = signals
+            .filter(|signal| signal.direction == direction)
+            .collect();
+        if direction == StrategyOutput::Hold || agreeing.is_empty() {
+            return Signal::hold();
+        }
+        Signal {
+            direction,
+            confidence: agreeing
+                .iter()
+                .fold(0.0, |total, signal| total + signal.confidence)
+                / agreeing.len() as f64,
+            stop: agreeing.iter().find_map(|signal| signal.stop),
+            target: agreeing.iter().find_map(|signal| signal.target),
+            size: agreeing.iter().find_map(|signal| signal.size),
+        }
+    }
+
+    //A buy or sell when some signals point one way and none the other
+    fn any_of(signals: &[Signal]) -> Signal {
+        let has = |side| signals.iter().any(|signal| signal.direction == side);
+        let direction = match (has(StrategyOutput::Buy), has(StrategyOutput::Sell)) {
+            (true, false) => StrategyOutput::Buy,
+            (false, true) => StrategyOutput::Sell,
+            _ => StrategyOutput::Hold,
+        };
+        Self::merge(direction, signals.iter())
+    }
+
+    //Buys count +weight, sells -weight, both scaled by the child's confidence, as a fraction of the total weight
+    fn weighted_score(&self) -> f64 {
+        let total: f64 = self.children.iter().map(|child| child.weight).sum();
+        let score = self.children.iter().fold(0.0, |score, child| {
+            let vote = child.weight * child.stance.confidence;
+            match child.stance.direction {
+                StrategyOutput::Buy => score + vote,
+                StrategyOutput::Sell => score - vote,
+                StrategyOutput::Hold => score,
+            }
+        });
+        score / total
+    }
+}
+
+impl RegisteredStrategy for Composite {
+    const NAME: &'static str = "Composite";
+    const DESCRIPTION: &'static str =
+        "Combines other strategies by majority, unanimous or weighted vote, any signal, or a filter in front of entry triggers";
+    const PARAMS: &'static [ParamSpec] = &[
+        ParamSpec {
+            name: "mode",
+            description: "\"majority\", \"unanimous\", \"weighted\", \"any\" or \"filter\" (the first child filters the buys of the rest, sells always go through)",
+        },
+        ParamSpec {
+            name: "children",
+            description: "List of { strategy, strategy_params, weight, timeframe } tables, strategy_params, weight (weighted only, default 1) and timeframe (default the stock's) are optional",
+        },
+        ParamSpec {
+            name: "threshold",
+            description: "Weighted only, fraction of the total weight the score has to reach to buy or sell (default 0.5)",
+        },
+    ];
+    type Params = CompositeParams;
+
+    fn from_params(params: CompositeParams) -> anyhow::Result<Self> {
+        let bad = |reason: String| {
+            Error::msg(format!(
+                "Bad strategy_params for {}: {}",
+                Self::NAME,
+                reason
+            ))
+        };
+        let least = if params.mode == CompositeMode::Filter {
+            2
+        } else {
+            1
+        };
+        if params.children.len() < least {
+            return Err(bad(format!(
+                "{:?} mode needs at least {} children",
+                params.mode, least
+            )));
+        }
+        let threshold = params.threshold.unwrap_or(DEFAULT_THRESHOLD);
+        if threshold <= 0.0 || threshold > 1.0 {
+            return Err(bad("threshold has to be above 0 and at most 1".to_string()));
+        }
+
+        let mut children = Vec::with_capacity(params.children.len());
+        for (i, child) in params.children.iter().enumerate() {
+            let weight = child.weight.unwrap_or(1.0);
+            if !weight.is_finite() || weight < 0.0 {
+                return Err(bad(format!("children[{}] weight can't be negative", i)));
+            }
+            let strategy = build_strategy(&child.strategy, child.strategy_params.as_ref())
+                .map_err(|e| bad(format!("children[{}]: {}", i, e)))?;
+            children.push(Child {
+                weight,
+                strategy,
+                timeframe: child.timeframe,
+                stance: Signal::hold(),
+                pending: Signal::hold(),
+            });
+        }
+        if params.mode == CompositeMode::Weighted && children.iter().all(|c| c.weight == 0.0) {
+            return Err(bad("at least one child needs a weight above 0".to_string()));
+        }
+
+        Ok(Self {
+            mode: params.mode,
+            threshold,
+            children,
+            position: 0.0,
+        })
+    }
+}
+
+impl StockStrategy for Composite {
+    fn save_state(&self) -> (Vec<u8>, String) {
+        (bincode::serialize(&self).unwrap(), Self::NAME.to_string())
+    }
+
+    fn set_position(&mut self, position: f64) {
+        self.position = position;
+        for child in &mut self.children {
+            child.strategy.set_position(position);
+        }
+    }
+
+    fn timeframes(&self) -> Vec<Timeframe> {
+        let mut timeframes: Vec<Timeframe> = self
+            .children
+            .iter()
+            .flat_map(|child| match child.timeframe {
+                Some(timeframe) => vec![timeframe],
+                //A composite inside a composite can have its own
+                None => child.strategy.timeframes(),
+            })
+            .collect();
+        timeframes.sort();
+        timeframes.dedup();
+        timeframes
+    }
+
+    fn run_timeframe(&mut self, timeframe: Timeframe, data: &Bar) -> anyhow::Result<()> {
+        for child in &mut self.children {
+            match child.timeframe {
+                Some(own) if own == timeframe => {
+                    let signal = child.strategy.run(data)?;
+                    if !signal.is_hold() {
+                        child.stance = signal;
+                        child.pending = signal;
+                    }
+                }
+                Some(_) => {}
+                None => child.strategy.run_timeframe(timeframe, data)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn run_backtest(
+        &mut self,
+        open: f64,
+        close: f64,
+        high: f64,
+        low: f64,
+        volume: f64,
+    ) -> StrategyOutput {
+        self.next_signal(open, close, high, low, volume).direction
+    }
+
+    fn next_signal(&mut self, open: f64, close: f64, high: f64, low: f64, volume: f64) -> Signal {
+        let signals: Vec<Signal> = self
+            .children
+            .iter_mut()
+            .map(|child| match child.timeframe {
+                Some(_) => std::mem::replace(&mut child.pending, Signal::hold()),
+                None => {
+                    let signal = child.strategy.next_signal(open, close, high, low, volume);
+                    if !signal.is_hold() {
+                        child.stance = signal;
+                    }
+                    signal
+                }
+            })
+            .collect();
+        let count = self.children.len();
+        let stances = || self.children.iter().map(|child| &child.stance);
+
+        match self.mode {
+            CompositeMode::Majority | CompositeMode::Unanimous => {
+                //Unanimous needs all of them, majority more than half
+                let needed = if self.mode == CompositeMode::Unanimous {
+                    count
+                } else {
+                    count / 2 + 1
+                };
+                let direction = if self.stances(StrategyOutput::Buy) >= needed {
+                    StrategyOutput::Buy
+                } else if self.stances(StrategyOutput::Sell) >= needed {
+                    StrategyOutput::Sell
+                } else {
+                    StrategyOutput::Hold
+                };
+                Self::merge(direction, stances())
+            }
+            CompositeMode::Weighted => {
+                let score = self.weighted_score();
+                let direction = if score >= self.threshold {
+                    StrategyOutput::Buy
+                } else if score <= -self.threshold {
+                    StrategyOutput::Sell
+                } else {
+                    StrategyOutput::Hold
+                };
+                //How sure the vote is, not how sure the children on the winning side are
+                Signal {
+                    confidence: score.abs().min(1.0),
+                    ..Self::merge(direction, stances())
+                }
+            }
+            CompositeMode::Any => Self::any_of(&signals),
+            CompositeMode::Filter => {
+                //Getting out is never blocked, only getting in has to agree with the filter
+                //A sell with nothing held is an entry too since it opens a short on stocks that allow it
+                let trigger = Self::any_of(&signals[1..]);
+                let entering = match trigger.direction {
+                    StrategyOutput::Buy => self.position >= 0.0,
+                    StrategyOutput::Sell => self.position <= 0.0,
+                    StrategyOutput::Hold => false,
+                };
+                if entering && self.children[0].stance.direction != trigger.direction {
+                    Signal::hold()
+                } else {
+                    trigger
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    const B: StrategyOutput = StrategyOutput::Buy;
+    const S: StrategyOutput = StrategyOutput::Sell;
+    const H: StrategyOutput = StrategyOutput::Hold;
+
+    //Says what it's told, one output per bar
+    struct Scripted(VecDeque<StrategyOutput>);
+
+    impl StockStrategy for Scripted {
+        fn run_backtest(&mut self, _: f64, _: f64, _: f64, _: f64, _: f64) -> StrategyOutput {
+            self.0.pop_front().unwrap_or(H)
+        }
+
+        fn save_state(&self) -> (Vec<u8>, String) {
+            (vec![], "Scripted".to_string())
+        }
+    }
+
+    fn composite(mode: CompositeMode, scripts: &[(f64, &[StrategyOutput])]) -> Composite {
+        Composite {
+            mode,
+            threshold: DEFAULT_THRESHOLD,
+            children: scripts
+                .iter()
+                .map(|(weight, script)| Child {
+                    weight: *weight,
+                    strategy: Box::new(Scripted(script.iter().copied().collect())),
+                    timeframe: None,
+                    stance: Signal::hold(),
+                    pending: Signal::hold(),
+                })
+                .collect(),
+            position: 0.0,
+        }
+    }
+
+    fn run(composite: &mut Composite, bars: usize) -> Vec<StrategyOutput> {
+        (0..bars)
+            .map(|_| composite.run_backtest(100.0, 100.0, 100.0, 100.0, 0.0))
+            .collect()
+    }
+
+    #[test]
+    fn majority_acts_once_enough_children_lean_the_same_way() {
+        let mut majority = composite(
+            CompositeMode::Majority,
+            &[(1.0, &[B, H, H]), (1.0, &[H, B, H]), (1.0, &[H, H, S])],
+        );
+        //One buy isn't a majority, the first child's buy still counts on the next bar
+        assert_eq!(run(&mut majority, 3), [H, B, B]);
+    }
+
+    #[test]
+    fn unanimous_needs_every_child() {
+        let mut unanimous = composite(
+            CompositeMode::Unanimous,
+            &[(1.0, &[B, H, H]), (1.0, &[H, B, H]), (1.0, &[H, H, B])],
+        );
+        assert_eq!(run(&mut unanimous, 3), [H, H, B]);
+    }
+
+    #[test]
+    fn weighted_goes_once_the_score_reaches_the_threshold() {
+        let mut weighted = composite(CompositeMode::Weighted, &[(3.0, &[H, B]), (1.0, &[B, H])]);
+        //A quarter of the weight, then all of it
+        let first = weighted.next_signal(100.0, 100.0, 100.0, 100.0, 0.0);
+        assert!(first.is_hold());
+        let second = weighted.next_signal(100.0, 100.0, 100.0, 100.0, 0.0);
+        assert_eq!(second.direction, B);
+        assert_eq!(second.confidence, 1.0);
+
+        let mut split = composite(CompositeMode::Weighted, &[(1.0, &[B]), (1.0, &[S])]);
+        assert_eq!(run(&mut split, 1), [H]);
+    }
+
+    #[test]
+    fn any_acts_on_a_lone_signal_but_not_on_a_disagreement() {
+        let mut any = composite(CompositeMode::Any, &[(1.0, &[B, B, H]), (1.0, &[H, S, H])]);
+        assert_eq!(run(&mut any, 3), [B, H, H]);
+    }
+
+    #[test]
+    fn filter_blocks_entries_against_it_but_never_exits() {
+        //Filter turns bearish on the second bar, the trigger keeps buying and then sells
+        let mut filter = composite(
+            CompositeMode::Filter,
+            &[(1.0, &[B, S, H]), (1.0, &[B, B, S])],
+        );
+        assert_eq!(run(&mut filter, 2), [B, H]);
+        //Holding, the trigger's sell is an exit
+        filter.set_position(10.0);
+        assert_eq!(run(&mut filter, 1), [S]);
+
+        //With nothing held that same sell opens a short, which the bearish filter allows
+        let mut short = composite(CompositeMode::Filter, &[(1.0, &[S, H]), (1.0, &[H, S])]);
+        assert_eq!(run(&mut short, 2), [H, S]);
+        //A bullish filter doesn't
+        let mut blocked = composite(CompositeMode::Filter, &[(1.0, &[B, H]), (1.0, &[H, S])]);
+        assert_eq!(run(&mut blocked, 2), [H, H]);
+    }
+
+    #[test]
+    fn filter_mode_needs_a_filter_and_a_trigger() {
+        let params = CompositeParams {
+            mode: CompositeMode::Filter,
+            children: CompositeParams::default().children[..1].to_vec(),
+            threshold: None,
+        };
+        assert!(Composite::from_params(params).is_err());
+    }
+}
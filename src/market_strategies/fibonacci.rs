@@ -0,0 +1,299 @@
+use crate::market_strategies::registry::{ParamSpec, RegisteredStrategy};
+use crate::market_strategies::{Signal, StockStrategy, StrategyOutput};
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use tracing::info;
+
+//Retracement ratios, measured back from the end of the swing
+const PROFIT_RATIO: f64 = 0.236;
+const HALF_WAY_BACK_RATIO: f64 = 0.5;
+const FAILURE_RATIO: f64 = 0.618;
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct FibonacciParams {
+    //A swing high (or low) is a bar with lower highs (or higher lows) on this many bars each side of it
+    pub swing_strength: usize,
+    //Swings that move less than this percent are noise and don't start a new leg
+    pub min_swing_pct: f64,
+    //How much leeway (as a retracement ratio) the price has when it comes to the half, failure, and profit zones
+    pub error_margin: f64,
+}
+
+impl Default for FibonacciParams {
+    fn default() -> Self {
+        Self {
+            swing_strength: 5,
+            min_swing_pct: 0.5,
+            error_margin: 0.05,
+        }
+    }
+}
+
+//The move between the last swing low and swing high, the retracement levels are measured on it
+#[derive(Serialize, Deserialize, Clone, Copy)]
+struct Leg {
+    //Low then high, otherwise high then low
+    going_up: bool,
+    high: f64,
+    low: f64,
+    //Price came back half way and the trade was signaled
+    entered: bool,
+}
+
+impl Leg {
+    //Price that has given back this ratio of the leg
+    fn level(&self, ratio: f64) -> f64 {
+        let range = self.high - self.low;
+        if self.going_up {
+            self.high - range * ratio
+        } else {
+            self.low + range * ratio
+        }
+    }
+
+    //How much of the leg price has given back, negative when it went past the end of the leg
+    fn retraced(&self, price: f64) -> f64 {
+        let range = self.high - self.low;
+        if self.going_up {
+            (self.high - price) / range
+        } else {
+            (price - self.low) / range
+        }
+    }
+}
+
+//Uptrends buy the pullback half way back and sell near the old high or when the pullback goes too deep
+//Downtrends are the mirror image, sell the bounce half way back and buy near the old low or when the bounce goes too high
+#[derive(Serialize, Deserialize)]
+pub struct FibonacciRetracement {
+    params: FibonacciParams,
+    //(high, low) of the last swing_strength * 2 + 1 bars
+    window: VecDeque<(f64, f64)>,
+    //Prices of the most recent swing high and low
+    last_high: Option<f64>,
+    last_low: Option<f64>,
+    leg: Option<Leg>,
+}
+
+impl FibonacciRetracement {
+    //Looks at the bar in the middle of the window, it's a swing once swing_strength bars on both sides are known
+    fn confirm_swings(&mut self) {
+        let strength = self.params.swing_strength;
+        if self.window.len() < strength * 2 + 1 {
+            return;
+        }
+        let (high, low) = self.window[strength];
+        let before = || self.window.range(..strength);
+        let after = || self.window.range(strength + 1..);
+
+        //Ties go to the earlier bar so a flat top only counts once
+        let swing_high = before().all(|(h, _)| *h < high) && after().all(|(h, _)| *h <= high);
+        let swing_low = before().all(|(_, l)| *l > low) && after().all(|(_, l)| *l >= low);
+
+        if swing_high {
+            self.last_high = Some(high);
+            if let Some(last_low) = self.last_low {
+                self.start_leg(true, high, last_low);
+            }
+        }
+        if swing_low {
+            self.last_low = Some(low);
+            if let Some(last_high) = self.last_high {
+                self.start_leg(false, last_high, low);
+            }
+        }
+    }
+
+    fn start_leg(&mut self, going_up: bool, high: f64, low: f64) {
+        if low <= 0.0 || (high - low) / low * 100.0 < self.params.min_swing_pct {
+            return;
+        }
+        info!(
+            "Detected {}, watching from {} to {}",
+            if going_up { "uptrend" } else { "downtrend" },
+            low,
+            high
+        );
+        self.leg = Some(Leg {
+            going_up,
+            high,
+            low,
+            entered: false,
+        });
+    }
+
+    //What the leg says to do at this close, an uptrend's signal is a downtrend's opposite
+    fn leg_signal(&mut self, close: f64) -> Signal {
+        let Some(leg) = self.leg.as_mut() else {
+            return Signal::hold();
+        };
+        let margin = self.params.error_margin;
+        let retraced = leg.retraced(close);
+        let (with_trend, against_trend) = if leg.going_up {
+            (StrategyOutput::Buy, StrategyOutput::Sell)
+        } else {
+            (StrategyOutput::Sell, StrategyOutput::Buy)
+        };
+
+        //Went back too far, the trend failed
+        //Only a leg that was entered has something to get out of, otherwise it's just a reset
+        if retraced >= FAILURE_RATIO + margin {
+            info!(
+                "Trend failed at {}, past {}, resetting",
+                close,
+                leg.level(FAILURE_RATIO + margin)
+            );
+            let entered = leg.entered;
+            self.leg = None;
+            return if entered {
+                against_trend.into()
+            } else {
+                Signal::hold()
+            };
+        }
+
+        if !leg.entered {
+            //Trend in limbo half way back, get in with the trend, it's wrong past the failure zone and done at the profit zone
+            if (retraced - HALF_WAY_BACK_RATIO).abs() <= margin {
+                leg.entered = true;
+                return Signal {
+                    stop: Some(leg.level(FAILURE_RATIO + margin)),
+                    target: Some(leg.level(PROFIT_RATIO - margin)),
+                    ..with_trend.into()
+                };
+            }
+            return Signal::hold();
+        }
+
+        //Trend continuing, take the profit
+        if retraced <= PROFIT_RATIO - margin {
+            info!("Trend continued to {}, resetting", close);
+            self.leg = None;
+            return against_trend.into();
+        }
+        Signal::hold()
+    }
+}
+
+impl RegisteredStrategy for FibonacciRetracement {
+    const NAME: &'static str = "Fibonacci";
+    const DESCRIPTION: &'static str =
+        "Trades pullbacks half way back into the last swing, in both trend directions";
+    const PARAMS: &'static [ParamSpec] = &[
+        ParamSpec {
+            name: "swing_strength",
+            description: "Bars on each side a swing high/low has to stand out from",
+        },
+        ParamSpec {
+            name: "min_swing_pct",
+            description: "Percent a swing has to move to be traded",
+        },
+        ParamSpec {
+            name: "error_margin",
+            description: "Leeway (as a retracement ratio) the price has around the half, failure and profit zones",
+        },
+    ];
+    type Params = FibonacciParams;
+
+    fn from_params(params: FibonacciParams) -> anyhow::Result<Self> {
+        if params.swing_strength == 0
+            || params.min_swing_pct < 0.0
+            || !(0.0..PROFIT_RATIO).contains(&params.error_margin)
+        {
+            return Err(Error::msg(format!(
+                "Bad strategy_params for {}: swing_strength has to be above 0, min_swing_pct can't be negative and error_margin has to be from 0 to under {}",
+                Self::NAME,
+                PROFIT_RATIO
+            )));
+        }
+        Ok(Self {
+            params,
+            window: VecDeque::new(),
+            last_high: None,
+            last_low: None,
+            leg: None,
+        })
+    }
+}
+
+impl StockStrategy for FibonacciRetracement {
+    fn run_backtest(
+        &mut self,
+        open: f64,
+        close: f64,
+        high: f64,
+        low: f64,
+        volume: f64,
+    ) -> StrategyOutput {
+        self.next_signal(open, close, high, low, volume).direction
+    }
+
+    fn next_signal(&mut self, _open: f64, close: f64, high: f64, low: f64, _volume: f64) -> Signal {
+        self.window.push_back((high, low));
+        if self.window.len() > self.params.swing_strength * 2 + 1 {
+            self.window.pop_front();
+        }
+        self.confirm_swings();
+
+        self.leg_signal(close)
+    }
+
+    fn save_state(&self) -> (Vec<u8>, String) {
+        (bincode::serialize(&self).unwrap(), Self::NAME.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_leg(going_up: bool, entered: bool) -> FibonacciRetracement {
+        let mut strategy = FibonacciRetracement::from_params(FibonacciParams::default()).unwrap();
+        strategy.leg = Some(Leg {
+            going_up,
+            high: 110.0,
+            low: 100.0,
+            entered,
+        });
+        strategy
+    }
+
+    #[test]
+    fn entered_leg_gets_out_when_the_trend_fails() {
+        let mut up = with_leg(true, true);
+        assert_eq!(up.leg_signal(102.0).direction, StrategyOutput::Sell);
+        assert!(up.leg.is_none());
+
+        let mut down = with_leg(false, true);
+        assert_eq!(down.leg_signal(108.0).direction, StrategyOutput::Buy);
+        assert!(down.leg.is_none());
+    }
+
+    #[test]
+    fn leg_that_was_never_entered_just_resets_when_it_fails() {
+        let mut up = with_leg(true, false);
+        assert!(up.leg_signal(102.0).is_hold());
+        assert!(up.leg.is_none());
+
+        let mut down = with_leg(false, false);
+        assert!(down.leg_signal(108.0).is_hold());
+        assert!(down.leg.is_none());
+    }
+
+    #[test]
+    fn half_way_back_enters_with_the_trend() {
+        let mut up = with_leg(true, false);
+        let signal = up.leg_signal(105.0);
+        assert_eq!(signal.direction, StrategyOutput::Buy);
+        assert!(up.leg.unwrap().entered);
+        //Stop past the failure zone, target at the profit zone
+        assert!((signal.stop.unwrap() - (110.0 - 10.0 * 0.668)).abs() < 1e-9);
+        assert!((signal.target.unwrap() - (110.0 - 10.0 * 0.186)).abs() < 1e-9);
+
+        //Then the profit zone takes it back out
+        assert_eq!(up.leg_signal(109.0).direction, StrategyOutput::Sell);
+        assert!(up.leg.is_none());
+    }
+}
@@ -0,0 +1,221 @@
+use crate::market_strategies::registry::{ParamSpec, RegisteredStrategy};
+use crate::market_strategies::{StockStrategy, StrategyOutput};
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use ta::indicators::MovingAverageConvergenceDivergence as Macd;
+use ta::Next;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum MacdMode {
+    //MACD line crossing the signal line, which is the histogram changing sign
+    //"histogram" is the same thing, still read so older configs keep working
+    #[serde(alias = "histogram")]
+    SignalCross,
+    //MACD line crossing zero, the fast EMA crossing the slow one
+    ZeroLine,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct MacdParams {
+    pub fast: usize,
+    pub slow: usize,
+    pub signal: usize,
+    pub mode: MacdMode,
+    //Signal cross only, how far the MACD line has to get past the signal line (the histogram past zero) for the cross to count
+    //Filters out tiny wiggles around the signal line, 0 when left out
+    pub histogram_threshold: Option<f64>,
+}
+
+impl Default for MacdParams {
+    fn default() -> Self {
+        Self {
+            fast: 12,
+            slow: 26,
+            signal: 9,
+            mode: MacdMode::SignalCross,
+            histogram_threshold: None,
+        }
+    }
+}
+
+//Only acts on the bar where the cross happens, holds in between
+#[derive(Serialize, Deserialize)]
+pub struct MacdCrossover {
+    params: MacdParams,
+    macd: Macd,
+    //Which side of the line the last bar was on, None until warmed up
+    above: Option<bool>,
+    bars_seen: usize,
+}
+
+impl RegisteredStrategy for MacdCrossover {
+    const NAME: &'static str = "MACD";
+    const DESCRIPTION: &'static str =
+        "Buys and sells on MACD crosses of the signal line or the zero line";
+    const PARAMS: &'static [ParamSpec] = &[
+        ParamSpec {
+            name: "fast",
+            description: "Bars in the fast EMA, has to be less than slow",
+        },
+        ParamSpec {
+            name: "slow",
+            description: "Bars in the slow EMA",
+        },
+        ParamSpec {
+            name: "signal",
+            description: "Bars in the signal line EMA",
+        },
+        ParamSpec {
+            name: "mode",
+            description: "\"signal_cross\" or \"zero_line\"",
+        },
+        ParamSpec {
+            name: "histogram_threshold",
+            description:
+                "signal_cross only, how far past the signal line the MACD line has to get to count",
+        },
+    ];
+    type Params = MacdParams;
+
+    fn from_params(params: MacdParams) -> anyhow::Result<Self> {
+        if params.fast >= params.slow {
+            return Err(Error::msg(format!(
+                "Bad strategy_params for {}: fast ({}) has to be shorter than slow ({})",
+                Self::NAME,
+                params.fast,
+                params.slow
+            )));
+        }
+        if params.histogram_threshold.unwrap_or(0.0) < 0.0 {
+            return Err(Error::msg(format!(
+                "Bad strategy_params for {}: histogram_threshold can't be negative",
+                Self::NAME
+            )));
+        }
+        let macd = Macd::new(params.fast, params.slow, params.signal).map_err(|_| {
+            Error::msg(format!(
+                "Bad strategy_params for {}: fast, slow and signal have to be above 0",
+                Self::NAME
+            ))
+        })?;
+
+        Ok(Self {
+            params,
+            macd,
+            above: None,
+            bars_seen: 0,
+        })
+    }
+}
+
+impl StockStrategy for MacdCrossover {
+    fn save_state(&self) -> (Vec<u8>, String) {
+        (bincode::serialize(&self).unwrap(), Self::NAME.to_string())
+    }
+
+    fn run_backtest(
+        &mut self,
+        _open: f64,
+        close: f64,
+        _high: f64,
+        _low: f64,
+        _volume: f64,
+    ) -> StrategyOutput {
+        let output = self.macd.next(close);
+        self.bars_seen += 1;
+        //The signal line needs the slow EMA settled plus its own period before crosses mean anything
+        if self.bars_seen < self.params.slow + self.params.signal {
+            return StrategyOutput::Hold;
+        }
+
+        let (value, threshold) = match self.params.mode {
+            MacdMode::SignalCross => (
+                output.histogram,
+                self.params.histogram_threshold.unwrap_or(0.0),
+            ),
+            MacdMode::ZeroLine => (output.macd, 0.0),
+        };
+
+        //Inside the threshold nothing changes, the line has to be clearly crossed
+        let above = if value > threshold {
+            true
+        } else if value < -threshold {
+            false
+        } else {
+            return StrategyOutput::Hold;
+        };
+
+        match self.above.replace(above) {
+            Some(false) if above => StrategyOutput::Buy,
+            Some(true) if !above => StrategyOutput::Sell,
+            _ => StrategyOutput::Hold,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn macd(mode: MacdMode, histogram_threshold: Option<f64>) -> MacdCrossover {
+        MacdCrossover::from_params(MacdParams {
+            fast: 3,
+            slow: 6,
+            signal: 3,
+            mode,
+            histogram_threshold,
+        })
+        .unwrap()
+    }
+
+    //Flat, then one smooth swing up and back down
+    fn prices() -> Vec<f64> {
+        let mut prices = vec![100.0; 20];
+        prices.extend((1..=60).map(|i| 100.0 + 10.0 * (i as f64 / 10.0).sin()));
+        prices
+    }
+
+    fn signals(mut strategy: MacdCrossover) -> Vec<(usize, StrategyOutput)> {
+        prices()
+            .into_iter()
+            .enumerate()
+            .map(|(i, close)| (i, strategy.run_backtest(close, close, close, close, 0.0)))
+            .filter(|(_, output)| *output != StrategyOutput::Hold)
+            .collect()
+    }
+
+    fn first_sell(strategy: MacdCrossover) -> usize {
+        signals(strategy)
+            .into_iter()
+            .find(|(_, output)| *output == StrategyOutput::Sell)
+            .unwrap()
+            .0
+    }
+
+    #[test]
+    fn signal_cross_turns_before_the_zero_line() {
+        let cross = first_sell(macd(MacdMode::SignalCross, None));
+        let zero = first_sell(macd(MacdMode::ZeroLine, None));
+        //The swing tops out at bar 35, the signal line catches the slowdown before it and zero only comes after
+        assert!(cross > 20 && cross < 35);
+        assert!(zero > 35);
+    }
+
+    #[test]
+    fn histogram_threshold_delays_the_cross() {
+        let plain = first_sell(macd(MacdMode::SignalCross, None));
+        let filtered = first_sell(macd(MacdMode::SignalCross, Some(0.05)));
+        assert!(filtered > plain);
+    }
+
+    #[test]
+    fn histogram_is_read_as_signal_cross() {
+        let params: MacdParams = toml::from_str(
+            "fast = 12\nslow = 26\nsignal = 9\nmode = \"histogram\"\nhistogram_threshold = 0.1",
+        )
+        .unwrap();
+        assert_eq!(params.mode, MacdMode::SignalCross);
+    }
+}
@@ -0,0 +1,381 @@
+use crate::bar_aggregator::Timeframe;
+use crate::market_strategies::registry::{ParamSpec, RegisteredStrategy};
+use anyhow::{Error, Ok};
+use apca::data::v2::stream::Bar;
+use num_decimal::Num;
+use serde::{Deserialize, Serialize};
+use ta::indicators::ExponentialMovingAverage;
+use ta::Next;
+
+pub mod bollinger;
+pub mod composite;
+pub mod fibonacci;
+pub mod macd;
+pub mod registry;
+pub mod rsi;
+pub mod volume_filter;
+pub mod vwap;
+
+/*
+pub trait CryptoStrategy {
+    fn run(&mut self, data: &Bar) -> StrategyOutput;
+    fn save_state(&self) -> (Vec<u8>, String);
+}
+*/
+
+//This trait is the base for all Strategies, if you want to implement one, make sure your struct implements this
+pub trait StockStrategy {
+    //Takes the raw values of a single bar, both live and backtested bars end up here
+    fn run_backtest(
+        &mut self,
+        open: f64,
+        close: f64,
+        high: f64,
+        low: f64,
+        volume: f64,
+    ) -> StrategyOutput;
+
+    //Strategies that know more than which way to go (how sure they are, where to get out) override this, the rest give full confidence signals
+    fn next_signal(&mut self, open: f64, close: f64, high: f64, low: f64, volume: f64) -> Signal {
+        self.run_backtest(open, close, high, low, volume).into()
+    }
+
+    //Unpacks a bar into next_signal so live trading and backtests share one code path
+    fn run(&mut self, data: &Bar) -> anyhow::Result<Signal> {
+        let price = |num: &Num| {
+            num.to_f64()
+                .ok_or_else(|| Error::msg("Could not convert bar price to f64"))
+        };
+        Ok(self.next_signal(
+            price(&data.open_price)?,
+            price(&data.close_price)?,
+            price(&data.high_price)?,
+            price(&data.low_price)?,
+            data.volume as f64,
+        ))
+    }
+    fn save_state(&self) -> (Vec<u8>, String);
+
+    //Told what the monitor holds before every bar (negative when short), for strategies that treat entries and exits differently
+    fn set_position(&mut self, _position: f64) {}
+
+    //Timeframes the strategy wants on top of its stock's timeframe, every finished bar of them goes to run_timeframe
+    fn timeframes(&self) -> Vec<Timeframe> {
+        vec![]
+    }
+
+    //Gets the bars of the timeframes asked for, before the stock's own bar when they finish together
+    fn run_timeframe(&mut self, _timeframe: Timeframe, _data: &Bar) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StrategyOutput {
+    Buy,
+    Sell,
+    Hold,
+}
+
+///What a strategy wants done on a bar, the monitor sizes buys with it and sells at its stop and target
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct Signal {
+    pub direction: StrategyOutput,
+    //0 to 1, how sure the strategy is, a buy gets that fraction of the stock's intensity
+    pub confidence: f64,
+    //Prices to sell the position at if it goes against the trade or gets to where the strategy expects
+    pub stop: Option<f64>,
+    pub target: Option<f64>,
+    //0 to 1, scales the buy on top of confidence
+    pub size: Option<f64>,
+}
+
+//Plain outputs are full confidence with nothing else to say
+impl From<StrategyOutput> for Signal {
+    fn from(direction: StrategyOutput) -> Self {
+        Self {
+            direction,
+            confidence: 1.0,
+            stop: None,
+            target: None,
+            size: None,
+        }
+    }
+}
+
+impl Signal {
+    pub fn hold() -> Self {
+        StrategyOutput::Hold.into()
+    }
+
+    pub fn is_hold(&self) -> bool {
+        self.direction == StrategyOutput::Hold
+    }
+
+    //Fraction of the stock's intensity the signal asks for
+    pub fn size_fraction(&self) -> f64 {
+        (self.confidence * self.size.unwrap_or(1.0)).clamp(0.0, 1.0)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SingleMovingAverageParams {
+    pub period: usize,
+}
+
+impl Default for SingleMovingAverageParams {
+    fn default() -> Self {
+        Self { period: 2 }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SingleMovingAverage {
+    ema: ExponentialMovingAverage, //The core math formula
+}
+
+impl RegisteredStrategy for SingleMovingAverage {
+    const NAME: &'static str = "Single Moving Average";
+    const DESCRIPTION: &'static str =
+        "Buys when the close is above its EMA and sells when it's below";
+    const PARAMS: &'static [ParamSpec] = &[ParamSpec {
+        name: "period",
+        description: "Bars in the EMA",
+    }];
+    type Params = SingleMovingAverageParams;
+
+    fn from_params(params: SingleMovingAverageParams) -> anyhow::Result<Self> {
+        Ok(Self {
+            ema: ExponentialMovingAverage::new(params.period).map_err(|_| {
+                Error::msg(
+                    "Bad strategy_params for Single Moving Average: period has to be above 0",
+                )
+            })?,
+        })
+    }
+}
+
+/*
+impl CryptoStrategy for SingleMovingAverage {
+    fn run(&mut self, data: &Bar) -> StrategyOutput {
+        //get the new average
+        let avg = self.ema.next(data);
+
+        if data.usd > avg {
+            return StrategyOutput::Buy;
+        }
+        if data.usd < avg {
+            return StrategyOutput::Sell;
+        }
+        StrategyOutput::Hold
+    }
+
+    fn save_state(&self) -> (Vec<u8>, String) {
+        (
+            bincode::serialize(&self).unwrap(),
+            "Single Moving Average".to_string(),
+        )
+    }
+}
+*/
+
+impl StockStrategy for SingleMovingAverage {
+    fn save_state(&self) -> (Vec<u8>, String) {
+        (bincode::serialize(&self).unwrap(), Self::NAME.to_string())
+    }
+
+    fn run_backtest(
+        &mut self,
+        _open: f64,
+        close: f64,
+        _high: f64,
+        _low: f64,
+        _volume: f64,
+    ) -> StrategyOutput {
+        //get the new average
+        let avg = self.ema.next(close);
+
+        if close > avg {
+            return StrategyOutput::Buy;
+        }
+        if close < avg {
+            return StrategyOutput::Sell;
+        }
+        StrategyOutput::Hold
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TwoMovingAveragesParams {
+    pub fast: usize,
+    pub slow: usize,
+}
+
+impl Default for TwoMovingAveragesParams {
+    fn default() -> Self {
+        Self { fast: 2, slow: 6 }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TwoMovingAverages {
+    ema_one: ExponentialMovingAverage,
+    ema_two: ExponentialMovingAverage,
+}
+
+impl RegisteredStrategy for TwoMovingAverages {
+    const NAME: &'static str = "Two Moving Averages";
+    const DESCRIPTION: &'static str =
+        "Buys when the fast EMA is above the slow one and sells when it's below";
+    const PARAMS: &'static [ParamSpec] = &[
+        ParamSpec {
+            name: "fast",
+            description: "Bars in the fast EMA, has to be less than slow",
+        },
+        ParamSpec {
+            name: "slow",
+            description: "Bars in the slow EMA",
+        },
+    ];
+    type Params = TwoMovingAveragesParams;
+
+    fn from_params(params: TwoMovingAveragesParams) -> anyhow::Result<Self> {
+        if params.fast >= params.slow {
+            return Err(Error::msg(format!(
+                "Bad strategy_params for Two Moving Averages: fast ({}) has to be shorter than slow ({})",
+                params.fast, params.slow
+            )));
+        }
+        Ok(Self {
+            ema_one: ExponentialMovingAverage::new(params.fast).map_err(|_| {
+                Error::msg("Bad strategy_params for Two Moving Averages: fast has to be above 0")
+            })?,
+            ema_two: ExponentialMovingAverage::new(params.slow)?,
+        })
+    }
+}
+
+/*
+impl CryptoStrategy for TwoMovingAverages {
+    fn run(&mut self, data: &CryptoMarketData) -> StrategyOutput {
+        let avg_one = self.ema_one.next(data.usd);
+        let avg_two = self.ema_two.next(data.usd);
+
+        if avg_one > avg_two {
+            return StrategyOutput::Buy;
+        }
+        if avg_one < avg_two {
+            return StrategyOutput::Sell;
+        }
+        StrategyOutput::Hold
+    }
+
+    fn save_state(&self) -> (Vec<u8>, String) {
+        (
+            bincode::serialize(&self).unwrap(),
+            "Two Moving Averages".to_string(),
+        )
+    }
+}
+*/
+
+impl StockStrategy for TwoMovingAverages {
+    fn save_state(&self) -> (Vec<u8>, String) {
+        (bincode::serialize(&self).unwrap(), Self::NAME.to_string())
+    }
+
+    fn run_backtest(
+        &mut self,
+        _open: f64,
+        close: f64,
+        _high: f64,
+        _low: f64,
+        _volume: f64,
+    ) -> StrategyOutput {
+        //get the new average
+
+        let avg_one = self.ema_one.next(close);
+        let avg_two = self.ema_two.next(close);
+
+        if avg_one > avg_two {
+            return StrategyOutput::Buy;
+        }
+        if avg_one < avg_two {
+            return StrategyOutput::Sell;
+        }
+        StrategyOutput::Hold
+    }
+}
+
+//Support and Resist has nothing to set, this only exists so a strategy_params table for it gets rejected
+#[derive(Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct SupportNResistParams {}
+
+#[derive(Serialize, Deserialize)]
+pub struct SupportNResist {
+    past_high: f64,
+    past_low: f64,
+    past_close: f64,
+    ran_before: bool,
+}
+
+impl SupportNResist {
+    pub fn new() -> Self {
+        Self {
+            past_high: 0.0,
+            past_low: 0.0,
+            past_close: 0.0,
+            ran_before: false,
+        }
+    }
+}
+
+impl RegisteredStrategy for SupportNResist {
+    const NAME: &'static str = "Support and Resist";
+    const DESCRIPTION: &'static str =
+        "Trades off the pivot point, support and resistance of the last bar";
+    const PARAMS: &'static [ParamSpec] = &[];
+    type Params = SupportNResistParams;
+
+    fn from_params(_params: SupportNResistParams) -> anyhow::Result<Self> {
+        Ok(Self::new())
+    }
+}
+
+impl StockStrategy for SupportNResist {
+    fn save_state(&self) -> (Vec<u8>, String) {
+        (bincode::serialize(&self).unwrap(), Self::NAME.to_string())
+    }
+
+    fn run_backtest(
+        &mut self,
+        _open: f64,
+        close: f64,
+        high: f64,
+        low: f64,
+        _volume: f64,
+    ) -> StrategyOutput {
+        if !self.ran_before {
+            self.ran_before = true;
+            self.past_high = high;
+            self.past_low = low;
+            self.past_close = close;
+            return StrategyOutput::Hold;
+        }
+        let current_price = close;
+
+        let center = (self.past_high + self.past_low + self.past_close) / 3.0;
+        let resistance = 2.0 * center - self.past_low;
+
+        if current_price >= resistance {
+            return StrategyOutput::Sell;
+        }
+        if current_price > center {
+            return StrategyOutput::Buy;
+        }
+        StrategyOutput::Hold
+    }
+}
@@ -0,0 +1,240 @@
+use crate::market_strategies::bollinger::BollingerBands;
+use crate::market_strategies::composite::Composite;
+use crate::market_strategies::fibonacci::FibonacciRetracement;
+use crate::market_strategies::macd::MacdCrossover;
+use crate::market_strategies::rsi::RelativeStrengthIndex;
+use crate::market_strategies::vwap::VwapReversion;
+use crate::market_strategies::{
+    SingleMovingAverage, StockStrategy, SupportNResist, TwoMovingAverages,
+};
+use anyhow::{Error, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+//Implemented by every strategy that can be picked in the config, gives the registry everything it needs to build, restore and describe it
+pub trait RegisteredStrategy:
+    StockStrategy + Serialize + DeserializeOwned + Send + Sync + 'static
+{
+    //What goes in a stock's strategy setting, also the name its state is saved under in the DB
+    const NAME: &'static str;
+    const DESCRIPTION: &'static str;
+    //One entry per key of Params
+    const PARAMS: &'static [ParamSpec];
+    //The strategy_params table, the default is used when a stock has no strategy_params
+    type Params: Serialize + DeserializeOwned + Default;
+
+    fn from_params(params: Self::Params) -> Result<Self>;
+}
+
+pub struct ParamSpec {
+    pub name: &'static str,
+    pub description: &'static str,
+}
+
+pub type BoxedStrategy = Box<dyn StockStrategy + Send + Sync>;
+
+pub struct StrategyEntry {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub params: &'static [ParamSpec],
+    build: fn(Option<&toml::Value>) -> Result<BoxedStrategy>,
+    restore: fn(&[u8]) -> bincode::Result<BoxedStrategy>,
+    defaults: fn() -> Result<toml::Value>,
+    resolved: fn(Option<&toml::Value>) -> Result<String>,
+}
+
+fn parse_params<T: RegisteredStrategy>(params: Option<&toml::Value>) -> Result<T::Params> {
+    //Leaving out strategy_params uses the strategy's defaults, a table has to set every key and nothing else
+    Ok(match params {
+        Some(params) => params
+            .clone()
+            .try_into()
+            .map_err(|e| Error::msg(format!("Bad strategy_params for {}: {}", T::NAME, e)))?,
+        None => T::Params::default(),
+    })
+}
+
+fn build<T: RegisteredStrategy>(params: Option<&toml::Value>) -> Result<BoxedStrategy> {
+    Ok(Box::new(T::from_params(parse_params::<T>(params)?)?))
+}
+
+fn resolved<T: RegisteredStrategy>(params: Option<&toml::Value>) -> Result<String> {
+    Ok(serde_json::to_string(&parse_params::<T>(params)?)?)
+}
+
+fn restore<T: RegisteredStrategy>(bytes: &[u8]) -> bincode::Result<BoxedStrategy> {
+    bincode::deserialize::<T>(bytes).map(|strategy| Box::new(strategy) as _)
+}
+
+fn defaults<T: RegisteredStrategy>() -> Result<toml::Value> {
+    Ok(toml::Value::try_from(T::Params::default())?)
+}
+
+impl StrategyEntry {
+    pub const fn of<T: RegisteredStrategy>() -> Self {
+        Self {
+            name: T::NAME,
+            description: T::DESCRIPTION,
+            params: T::PARAMS,
+            build: build::<T>,
+            restore: restore::<T>,
+            defaults: defaults::<T>,
+            resolved: resolved::<T>,
+        }
+    }
+
+    pub fn build(&self, params: Option<&toml::Value>) -> Result<BoxedStrategy> {
+        (self.build)(params)
+    }
+
+    //Rebuilds the strategy from the bytes its save_state gave
+    pub fn restore(&self, bytes: &[u8]) -> bincode::Result<BoxedStrategy> {
+        (self.restore)(bytes)
+    }
+
+    //The strategy_params used when a stock doesn't set any
+    pub fn defaults(&self) -> Result<toml::Value> {
+        (self.defaults)()
+    }
+
+    //The params the strategy is built with, defaults filled in, as JSON so two configs can be compared
+    pub fn resolved_params(&self, params: Option<&toml::Value>) -> Result<String> {
+        (self.resolved)(params)
+    }
+}
+
+///Every strategy the config can use, adding a strategy only needs a line here
+pub static STRATEGIES: &[StrategyEntry] = &[
+    StrategyEntry::of::<SingleMovingAverage>(),
+    StrategyEntry::of::<TwoMovingAverages>(),
+    StrategyEntry::of::<SupportNResist>(),
+    StrategyEntry::of::<FibonacciRetracement>(),
+    StrategyEntry::of::<RelativeStrengthIndex>(),
+    StrategyEntry::of::<MacdCrossover>(),
+    StrategyEntry::of::<BollingerBands>(),
+    StrategyEntry::of::<VwapReversion>(),
+    StrategyEntry::of::<Composite>(),
+];
+
+pub fn find_strategy(name: &str) -> Result<&'static StrategyEntry> {
+    STRATEGIES
+        .iter()
+        .find(|entry| entry.name == name)
+        .ok_or_else(|| {
+            Error::msg(format!(
+                "Unknown strategy: {}, expected one of: {}",
+                name,
+                STRATEGIES
+                    .iter()
+                    .map(|entry| entry.name)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ))
+        })
+}
+
+///Builds the strategy with the given name, params is the stock's strategy_params table from the config
+pub fn build_strategy(name: &str, params: Option<&toml::Value>) -> Result<BoxedStrategy> {
+    find_strategy(name)?.build(params)
+}
+
+///Saved with the strategy's state, a restored strategy carries its old params so it's only used when these match
+pub fn resolved_params(name: &str, params: Option<&toml::Value>) -> Result<String> {
+    find_strategy(name)?.resolved_params(params)
+}
+
+//toml prints arrays of tables over several lines, this keeps a default on one line
+fn inline(value: &toml::Value) -> String {
+    match value {
+        toml::Value::Table(table) => format!(
+            "{{ {} }}",
+            table
+                .iter()
+                .map(|(key, value)| format!("{} = {}", key, inline(value)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        toml::Value::Array(values) => format!(
+            "[{}]",
+            values.iter().map(inline).collect::<Vec<_>>().join(", ")
+        ),
+        value => value.to_string(),
+    }
+}
+
+///Prints every strategy with its params and their defaults, used by "trade-bot strategies"
+pub fn print_strategies() -> Result<()> {
+    for entry in STRATEGIES {
+        println!("{}: {}", entry.name, entry.description);
+        let defaults = entry.defaults()?;
+        if entry.params.is_empty() {
+            println!("    (no strategy_params)");
+        }
+        for param in entry.params {
+            let default = defaults
+                .get(param.name)
+                .map(|value| format!("{} (default {})", value.type_str(), inline(value)))
+                //Optional params have nothing to show
+                .unwrap_or_else(|| "(optional)".to_string());
+            println!("    {} {}: {}", param.name, default, param.description);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::market_strategies::StrategyOutput;
+
+    //A wave that goes both ways, enough for every strategy to warm up and give some signals
+    fn prices(from: usize, to: usize) -> impl Iterator<Item = f64> {
+        (from..to).map(|i| 100.0 + 10.0 * (i as f64 / 7.0).sin() + (i % 3) as f64)
+    }
+
+    fn run(strategy: &mut BoxedStrategy, from: usize, to: usize) -> Vec<StrategyOutput> {
+        prices(from, to)
+            .map(|close| strategy.run_backtest(close, close, close + 0.5, close - 0.5, 1000.0))
+            .collect()
+    }
+
+    #[test]
+    fn every_strategy_picks_up_where_it_was_saved() {
+        for entry in STRATEGIES {
+            let mut original = entry.build(None).unwrap();
+            run(&mut original, 0, 100);
+
+            let (bytes, name) = original.save_state();
+            assert_eq!(name, entry.name);
+            let mut restored = find_strategy(&name).unwrap().restore(&bytes).unwrap();
+            assert_eq!(restored.save_state().0, bytes, "{}", entry.name);
+            assert_eq!(
+                run(&mut original, 100, 200),
+                run(&mut restored, 100, 200),
+                "{}",
+                entry.name
+            );
+        }
+    }
+
+    #[test]
+    fn defaults_build_and_resolve_the_same_as_no_params() {
+        for entry in STRATEGIES {
+            let defaults = entry.defaults().unwrap();
+            entry.build(Some(&defaults)).unwrap();
+            assert_eq!(
+                entry.resolved_params(Some(&defaults)).unwrap(),
+                entry.resolved_params(None).unwrap(),
+                "{}",
+                entry.name
+            );
+        }
+    }
+
+    #[test]
+    fn params_have_to_fit_the_strategy() {
+        let unknown: toml::Value = toml::from_str("period = 14\nwhatever = 1").unwrap();
+        assert!(build_strategy("Relative Strength Index", Some(&unknown)).is_err());
+        assert!(build_strategy("Not A Strategy", None).is_err());
+    }
+}
@@ -0,0 +1,203 @@
+use crate::market_strategies::registry::{ParamSpec, RegisteredStrategy};
+use crate::market_strategies::{StockStrategy, StrategyOutput};
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use ta::indicators::RelativeStrengthIndex as Rsi;
+use ta::Next;
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RelativeStrengthIndexParams {
+    pub period: usize,
+    pub overbought: f64,
+    pub oversold: f64,
+    //Wait for the RSI to come back out of the overbought/oversold zone before acting instead of acting as soon as it gets there
+    pub confirm_cross_back: bool,
+}
+
+impl Default for RelativeStrengthIndexParams {
+    fn default() -> Self {
+        Self {
+            period: 14,
+            overbought: 70.0,
+            oversold: 30.0,
+            confirm_cross_back: false,
+        }
+    }
+}
+
+//Mean reversion, buys when the stock has been sold off too hard and sells when it's been bought up too much
+#[derive(Serialize, Deserialize)]
+pub struct RelativeStrengthIndex {
+    params: RelativeStrengthIndexParams,
+    rsi: Rsi,
+    previous: Option<f64>,
+    //The RSI means nothing until it has seen a full period
+    bars_seen: usize,
+}
+
+impl RegisteredStrategy for RelativeStrengthIndex {
+    const NAME: &'static str = "Relative Strength Index";
+    const DESCRIPTION: &'static str =
+        "Buys when the RSI is oversold and sells when it's overbought";
+    const PARAMS: &'static [ParamSpec] = &[
+        ParamSpec {
+            name: "period",
+            description: "Bars in the RSI",
+        },
+        ParamSpec {
+            name: "overbought",
+            description: "RSI level (0-100) to sell at",
+        },
+        ParamSpec {
+            name: "oversold",
+            description: "RSI level (0-100) to buy at, has to be less than overbought",
+        },
+        ParamSpec {
+            name: "confirm_cross_back",
+            description: "Only act once the RSI crosses back out of the overbought/oversold zone",
+        },
+    ];
+    type Params = RelativeStrengthIndexParams;
+
+    fn from_params(params: RelativeStrengthIndexParams) -> anyhow::Result<Self> {
+        if !(0.0..=100.0).contains(&params.oversold)
+            || !(0.0..=100.0).contains(&params.overbought)
+            || params.oversold >= params.overbought
+        {
+            return Err(Error::msg(format!(
+                "Bad strategy_params for {}: oversold ({}) has to be under overbought ({}) and both have to be 0-100",
+                Self::NAME,
+                params.oversold,
+                params.overbought
+            )));
+        }
+        let rsi = Rsi::new(params.period).map_err(|_| {
+            Error::msg(format!(
+                "Bad strategy_params for {}: period has to be above 0",
+                Self::NAME
+            ))
+        })?;
+
+        Ok(Self {
+            params,
+            rsi,
+            previous: None,
+            bars_seen: 0,
+        })
+    }
+}
+
+impl StockStrategy for RelativeStrengthIndex {
+    fn save_state(&self) -> (Vec<u8>, String) {
+        (bincode::serialize(&self).unwrap(), Self::NAME.to_string())
+    }
+
+    fn run_backtest(
+        &mut self,
+        _open: f64,
+        close: f64,
+        _high: f64,
+        _low: f64,
+        _volume: f64,
+    ) -> StrategyOutput {
+        let rsi = self.rsi.next(close);
+        self.bars_seen += 1;
+        let previous = self.previous.replace(rsi);
+        if self.bars_seen < self.params.period {
+            return StrategyOutput::Hold;
+        }
+
+        let (buy, sell) = if self.params.confirm_cross_back {
+            match previous {
+                Some(previous) => (
+                    previous < self.params.oversold && rsi >= self.params.oversold,
+                    previous > self.params.overbought && rsi <= self.params.overbought,
+                ),
+                None => (false, false),
+            }
+        } else {
+            (rsi <= self.params.oversold, rsi >= self.params.overbought)
+        };
+
+        if buy {
+            return StrategyOutput::Buy;
+        }
+        if sell {
+            return StrategyOutput::Sell;
+        }
+        StrategyOutput::Hold
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rsi(confirm_cross_back: bool) -> RelativeStrengthIndex {
+        RelativeStrengthIndex::from_params(RelativeStrengthIndexParams {
+            period: 5,
+            confirm_cross_back,
+            ..RelativeStrengthIndexParams::default()
+        })
+        .unwrap()
+    }
+
+    fn run(strategy: &mut RelativeStrengthIndex, prices: &[f64]) -> Vec<StrategyOutput> {
+        prices
+            .iter()
+            .map(|&close| strategy.run_backtest(close, close, close, close, 0.0))
+            .collect()
+    }
+
+    //Slides for ten bars then bounces back for five
+    fn sell_off_and_bounce() -> Vec<f64> {
+        let mut prices: Vec<f64> = (0..10).map(|i| 100.0 - i as f64).collect();
+        prices.extend((1..=5).map(|i| 91.0 + 2.0 * i as f64));
+        prices
+    }
+
+    #[test]
+    fn acts_as_soon_as_it_reaches_the_zone() {
+        let outputs = run(&mut rsi(false), &sell_off_and_bounce());
+        //Nothing until the RSI has seen a full period
+        assert!(outputs[..4]
+            .iter()
+            .all(|output| *output == StrategyOutput::Hold));
+        assert_eq!(outputs[4], StrategyOutput::Buy);
+        assert_eq!(outputs[9], StrategyOutput::Buy);
+
+        let rally: Vec<f64> = (0..10).map(|i| 100.0 + i as f64).collect();
+        let outputs = run(&mut rsi(false), &rally);
+        assert_eq!(outputs[9], StrategyOutput::Sell);
+    }
+
+    #[test]
+    fn confirm_cross_back_waits_for_the_rsi_to_leave_the_zone() {
+        let outputs = run(&mut rsi(true), &sell_off_and_bounce());
+        //Still oversold the whole way down
+        assert!(outputs[..10]
+            .iter()
+            .all(|output| *output == StrategyOutput::Hold));
+        let buys: Vec<usize> = outputs
+            .iter()
+            .enumerate()
+            .filter(|(_, output)| **output == StrategyOutput::Buy)
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(buys.len(), 1);
+        assert!(buys[0] >= 10);
+    }
+
+    #[test]
+    fn oversold_has_to_be_under_overbought() {
+        assert!(
+            RelativeStrengthIndex::from_params(RelativeStrengthIndexParams {
+                oversold: 70.0,
+                overbought: 30.0,
+                ..RelativeStrengthIndexParams::default()
+            })
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,128 @@
+use crate::market_strategies::{Signal, StrategyOutput};
+use anyhow::{Error, Result};
+use serde::Deserialize;
+use std::collections::VecDeque;
+
+///Set as volume_filter on a stock in the config, works with any strategy
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct VolumeFilterConfig {
+    //Bars the average volume is taken over
+    pub lookback: usize,
+    //Signals on bars with less than this fraction of the average volume are ignored
+    pub min_ratio: f64,
+}
+
+//Turns entries into holds on bars that traded too little to trust, getting out is never blocked
+//Not saved to the DB, after a restart it lets everything through until it has seen lookback bars again
+pub struct VolumeFilter {
+    config: VolumeFilterConfig,
+    volumes: VecDeque<f64>,
+    //What the monitor holds, negative when short, tells entries from exits
+    position: f64,
+}
+
+impl VolumeFilter {
+    pub fn new(config: VolumeFilterConfig) -> Result<Self> {
+        if config.lookback == 0 || config.min_ratio < 0.0 {
+            return Err(Error::msg(
+                "Bad volume_filter: lookback has to be above 0 and min_ratio can't be negative",
+            ));
+        }
+        Ok(Self {
+            config,
+            volumes: VecDeque::new(),
+            position: 0.0,
+        })
+    }
+
+    pub fn set_position(&mut self, position: f64) {
+        self.position = position;
+    }
+
+    //Returns the signal to act on, the bar is compared against the bars before it
+    pub fn apply(&mut self, volume: f64, signal: Signal) -> Signal {
+        let low_volume = self.volumes.len() == self.config.lookback && {
+            let average = self.volumes.iter().sum::<f64>() / self.volumes.len() as f64;
+            volume < average * self.config.min_ratio
+        };
+
+        self.volumes.push_back(volume);
+        if self.volumes.len() > self.config.lookback {
+            self.volumes.pop_front();
+        }
+
+        //A sell with nothing held is an entry too since it opens a short on stocks that allow it
+        let entering = match signal.direction {
+            StrategyOutput::Buy => self.position >= 0.0,
+            StrategyOutput::Sell => self.position <= 0.0,
+            StrategyOutput::Hold => false,
+        };
+        if low_volume && entering {
+            Signal::hold()
+        } else {
+            signal
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter() -> VolumeFilter {
+        let mut filter = VolumeFilter::new(VolumeFilterConfig {
+            lookback: 3,
+            min_ratio: 0.5,
+        })
+        .unwrap();
+        for _ in 0..3 {
+            filter.apply(100.0, Signal::hold());
+        }
+        filter
+    }
+
+    #[test]
+    fn entries_on_low_volume_are_held() {
+        let mut filter = filter();
+        assert!(filter.apply(10.0, StrategyOutput::Buy.into()).is_hold());
+        assert!(filter.apply(10.0, StrategyOutput::Sell.into()).is_hold());
+        //Back to normal volume
+        let mut filter = self::filter();
+        assert_eq!(
+            filter.apply(60.0, StrategyOutput::Buy.into()).direction,
+            StrategyOutput::Buy
+        );
+    }
+
+    #[test]
+    fn exits_on_low_volume_go_through() {
+        let mut filter = filter();
+        filter.set_position(10.0);
+        assert_eq!(
+            filter.apply(10.0, StrategyOutput::Sell.into()).direction,
+            StrategyOutput::Sell
+        );
+        filter.set_position(-10.0);
+        assert_eq!(
+            filter.apply(10.0, StrategyOutput::Buy.into()).direction,
+            StrategyOutput::Buy
+        );
+        //Adding to a position is still an entry
+        assert!(filter.apply(10.0, StrategyOutput::Sell.into()).is_hold());
+    }
+
+    #[test]
+    fn lets_everything_through_until_it_has_the_lookback() {
+        let mut filter = VolumeFilter::new(VolumeFilterConfig {
+            lookback: 3,
+            min_ratio: 0.5,
+        })
+        .unwrap();
+        filter.apply(100.0, Signal::hold());
+        assert_eq!(
+            filter.apply(1.0, StrategyOutput::Buy.into()).direction,
+            StrategyOutput::Buy
+        );
+    }
+}
@@ -0,0 +1,158 @@
+use crate::market_strategies::registry::{ParamSpec, RegisteredStrategy};
+use crate::market_strategies::{StockStrategy, StrategyOutput};
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct VwapParams {
+    //Bars the VWAP is taken over
+    pub period: usize,
+    //How far under the VWAP (in percent) the close has to drop to buy
+    pub entry_pct: f64,
+}
+
+impl Default for VwapParams {
+    fn default() -> Self {
+        Self {
+            period: 30,
+            entry_pct: 0.2,
+        }
+    }
+}
+
+//Buys when price is stretched below the volume weighted average and sells once it gets back to it
+//The strategy doesn't see time stamps so the VWAP is rolling over the last period bars instead of resetting every session
+#[derive(Serialize, Deserialize)]
+pub struct VwapReversion {
+    params: VwapParams,
+    //(typical price * volume, volume) of the bars in the window
+    window: VecDeque<(f64, f64)>,
+}
+
+impl RegisteredStrategy for VwapReversion {
+    const NAME: &'static str = "VWAP Reversion";
+    const DESCRIPTION: &'static str =
+        "Buys when the close drops entry_pct under the rolling VWAP and sells when it gets back to it";
+    const PARAMS: &'static [ParamSpec] = &[
+        ParamSpec {
+            name: "period",
+            description: "Bars in the rolling VWAP",
+        },
+        ParamSpec {
+            name: "entry_pct",
+            description: "Percent under the VWAP the close has to be to buy",
+        },
+    ];
+    type Params = VwapParams;
+
+    fn from_params(params: VwapParams) -> anyhow::Result<Self> {
+        if params.period == 0 || params.entry_pct < 0.0 {
+            return Err(Error::msg(format!(
+                "Bad strategy_params for {}: period has to be above 0 and entry_pct can't be negative",
+                Self::NAME
+            )));
+        }
+        Ok(Self {
+            params,
+            window: VecDeque::new(),
+        })
+    }
+}
+
+impl StockStrategy for VwapReversion {
+    fn save_state(&self) -> (Vec<u8>, String) {
+        (bincode::serialize(&self).unwrap(), Self::NAME.to_string())
+    }
+
+    fn run_backtest(
+        &mut self,
+        _open: f64,
+        close: f64,
+        high: f64,
+        low: f64,
+        volume: f64,
+    ) -> StrategyOutput {
+        let typical_price = (high + low + close) / 3.0;
+        self.window.push_back((typical_price * volume, volume));
+        if self.window.len() > self.params.period {
+            self.window.pop_front();
+        }
+        if self.window.len() < self.params.period {
+            return StrategyOutput::Hold;
+        }
+
+        let (price_volume, total_volume) = self
+            .window
+            .iter()
+            .fold((0.0, 0.0), |(pv, v), (bar_pv, bar_v)| {
+                (pv + bar_pv, v + bar_v)
+            });
+        //Nothing traded in the whole window, there's no average to revert to
+        if total_volume == 0.0 {
+            return StrategyOutput::Hold;
+        }
+        let vwap = price_volume / total_volume;
+
+        if close <= vwap * (1.0 - self.params.entry_pct / 100.0) {
+            return StrategyOutput::Buy;
+        }
+        if close >= vwap {
+            return StrategyOutput::Sell;
+        }
+        StrategyOutput::Hold
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vwap() -> VwapReversion {
+        VwapReversion::from_params(VwapParams {
+            period: 3,
+            entry_pct: 1.0,
+        })
+        .unwrap()
+    }
+
+    fn run(strategy: &mut VwapReversion, bars: &[(f64, f64)]) -> Vec<StrategyOutput> {
+        bars.iter()
+            .map(|&(close, volume)| strategy.run_backtest(close, close, close, close, volume))
+            .collect()
+    }
+
+    #[test]
+    fn buys_entry_pct_under_the_vwap_and_sells_back_at_it() {
+        let outputs = run(
+            &mut vwap(),
+            &[
+                (100.0, 100.0),
+                (100.0, 100.0),
+                (98.0, 100.0),
+                (98.5, 100.0),
+                (99.5, 100.0),
+            ],
+        );
+        assert_eq!(outputs[..2], [StrategyOutput::Hold, StrategyOutput::Hold]);
+        //VWAP 99.33, 98 is more than 1% under it
+        assert_eq!(outputs[2], StrategyOutput::Buy);
+        //Under the VWAP but not by enough
+        assert_eq!(outputs[3], StrategyOutput::Hold);
+        assert_eq!(outputs[4], StrategyOutput::Sell);
+    }
+
+    #[test]
+    fn heavy_bars_pull_the_vwap_to_them() {
+        //A plain average would be 98.33, the volume puts the VWAP right on the last close
+        let outputs = run(&mut vwap(), &[(100.0, 1.0), (100.0, 1.0), (95.0, 1000.0)]);
+        assert_eq!(outputs[2], StrategyOutput::Hold);
+    }
+
+    #[test]
+    fn no_volume_means_no_signal() {
+        let outputs = run(&mut vwap(), &[(100.0, 0.0), (100.0, 0.0), (90.0, 0.0)]);
+        assert!(outputs.iter().all(|output| *output == StrategyOutput::Hold));
+    }
+}
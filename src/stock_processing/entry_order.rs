@@ -0,0 +1,75 @@
+use crate::broker::{OrderSide, OrderType};
+use anyhow::{Error, Result};
+use serde::Deserialize;
+
+///Set as entry_order on a stock in the config, the kind of order that opens a position
+///Offsets are percents of the last close in the direction of the trade, up for buys and down for shorts
+#[derive(Deserialize, Clone, Copy, Debug, Default)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum EntryOrder {
+    #[default]
+    Market,
+    //A positive offset is a marketable limit, it fills right away but never more than offset_pct past the close
+    //A negative one waits for the price to come back to it
+    Limit {
+        offset_pct: f64,
+    },
+    //Gets in once the price has moved offset_pct past the close
+    Stop {
+        offset_pct: f64,
+    },
+    //Like stop, but won't fill more than limit_offset_pct past the stop price
+    StopLimit {
+        offset_pct: f64,
+        limit_offset_pct: f64,
+    },
+}
+
+impl EntryOrder {
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            EntryOrder::Stop { offset_pct } if *offset_pct < 0.0 => Err(Error::msg(
+                "Bad entry_order: a stop's offset_pct can't be negative",
+            )),
+            EntryOrder::StopLimit {
+                offset_pct,
+                limit_offset_pct,
+            } if *offset_pct < 0.0 || *limit_offset_pct < 0.0 => Err(Error::msg(
+                "Bad entry_order: offset_pct and limit_offset_pct can't be negative",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn is_limit(&self) -> bool {
+        matches!(self, EntryOrder::Limit { .. })
+    }
+
+    //The order to send when entering on this side at this close
+    pub fn order_type(&self, side: OrderSide, close: f64) -> OrderType {
+        let direction = match side {
+            OrderSide::Buy => 1.0,
+            OrderSide::Sell => -1.0,
+        };
+        let past = |price: f64, percent: f64| price * (1.0 + direction * percent / 100.0);
+        match *self {
+            EntryOrder::Market => OrderType::Market,
+            EntryOrder::Limit { offset_pct } => OrderType::Limit {
+                limit_price: past(close, offset_pct),
+            },
+            EntryOrder::Stop { offset_pct } => OrderType::Stop {
+                stop_price: past(close, offset_pct),
+            },
+            EntryOrder::StopLimit {
+                offset_pct,
+                limit_offset_pct,
+            } => {
+                let stop_price = past(close, offset_pct);
+                OrderType::StopLimit {
+                    stop_price,
+                    limit_price: past(stop_price, limit_offset_pct),
+                }
+            }
+        }
+    }
+}
@@ -1,2 +1,6 @@
+pub mod entry_order;
+pub mod reconcile;
+pub mod sizing;
 pub mod stock_monitor;
 pub mod stock_ticker_loop;
+pub mod trailing_stop;
@@ -0,0 +1,89 @@
+use crate::broker::{Broker, OpenOrder};
+use crate::stock_processing::stock_monitor::StockMonitor;
+use anyhow::{Error, Result};
+use serde::Deserialize;
+use sled::Db;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use tracing::{info, warn};
+
+///Set as reconcile_policy in stock_engine_config, what happens at startup when the DB and the broker disagree about a stock
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ReconcilePolicy {
+    //The monitors take the broker's positions, orders the bot didn't place are left alone
+    #[default]
+    TrustBroker,
+    //The monitors keep what the DB says, the differences are only logged
+    TrustDb,
+    //Won't start until they're sorted out by hand
+    Halt,
+}
+
+//Ran before the live loop, checks every monitor's saved state against the positions and open orders at the broker
+//A manual trade or an order that went wrong while the bot was down would otherwise stay in the DB for good
+pub fn reconcile(
+    monitors: &HashMap<String, Arc<RwLock<StockMonitor>>>,
+    broker: &Arc<dyn Broker + Send + Sync>,
+    db: &Db,
+    policy: ReconcilePolicy,
+) -> Result<()> {
+    info!(
+        "Checking saved stock state against the broker ({:?})",
+        policy
+    );
+    let positions = broker.positions()?;
+    let open_orders = broker.open_orders()?;
+
+    for position in &positions {
+        if !monitors.contains_key(&position.symbol) {
+            info!(
+                "{} shares of {} at the broker aren't traded by the bot, leaving them alone",
+                position.quantity, &position.symbol
+            );
+        }
+    }
+
+    let mut symbols: Vec<&String> = monitors.keys().collect();
+    symbols.sort();
+    let mut mismatched: Vec<String> = vec![];
+    for symbol in symbols {
+        let mut monitor = monitors[symbol].write().unwrap();
+        let held = positions.iter().find(|position| &position.symbol == symbol);
+        let orders: Vec<&OpenOrder> = open_orders
+            .iter()
+            .filter(|order| &order.symbol == symbol)
+            .collect();
+
+        let discrepancies = monitor.reconcile(held, &orders, policy);
+        for discrepancy in &discrepancies {
+            warn!("[{}]: {}", symbol, discrepancy);
+        }
+        if !discrepancies.is_empty() {
+            mismatched.push(symbol.clone());
+        }
+        //Saved now so a crash before the stock's first bar doesn't bring the old state back
+        if policy != ReconcilePolicy::Halt {
+            let _ = db.insert(symbol.as_bytes(), monitor.save_state().to_bytes());
+        }
+    }
+
+    if mismatched.is_empty() {
+        info!("Saved stock state matches the broker");
+        return Ok(());
+    }
+    match policy {
+        ReconcilePolicy::Halt => Err(Error::msg(format!(
+            "The DB and the broker disagree on {:?}, sort them out or change reconcile_policy",
+            mismatched
+        ))),
+        ReconcilePolicy::TrustBroker => {
+            info!("Took the broker's positions for {:?}", mismatched);
+            Ok(())
+        }
+        ReconcilePolicy::TrustDb => {
+            info!("Kept the DB's positions for {:?}", mismatched);
+            Ok(())
+        }
+    }
+}
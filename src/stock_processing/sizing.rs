@@ -0,0 +1,61 @@
+use anyhow::{Error, Result};
+use serde::Deserialize;
+
+//Alpaca takes fractional quantities down to this many decimals, orders are rounded down to it
+const FRACTION_DECIMALS: i32 = 6;
+
+///Set as sizing on a stock in the config, picks how big a position a full confidence signal opens
+#[derive(Deserialize, Clone, Copy, Debug, Default)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Sizing {
+    //intensity whole shares
+    #[default]
+    Shares,
+    //This many dollars worth of the stock, fractional shares
+    Notional {
+        dollars: f64,
+    },
+    //This percent of the account's equity (cash plus positions), fractional shares
+    PercentEquity {
+        percent: f64,
+    },
+}
+
+impl Sizing {
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            Sizing::Notional { dollars } if *dollars <= 0.0 => {
+                Err(Error::msg("Bad sizing: dollars has to be above 0"))
+            }
+            Sizing::PercentEquity { percent } if *percent <= 0.0 || *percent > 100.0 => Err(
+                Error::msg("Bad sizing: percent has to be above 0 and at most 100"),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn fractional(&self) -> bool {
+        !matches!(self, Sizing::Shares)
+    }
+
+    //Dollars a full size position is worth, None when it's counted in whole shares instead
+    //Equity is only asked for when the size depends on it, live that's a call to the broker
+    pub fn dollars(&self, equity: impl FnOnce() -> Result<f64>) -> Result<Option<f64>> {
+        Ok(match self {
+            Sizing::Shares => None,
+            Sizing::Notional { dollars } => Some(*dollars),
+            Sizing::PercentEquity { percent } => Some(equity()? * percent / 100.0),
+        })
+    }
+
+    //Rounds a quantity down to what can be ordered, whole shares unless fractional is true
+    pub fn round_down(quantity: f64, fractional: bool) -> f64 {
+        if fractional {
+            let scale = 10f64.powi(FRACTION_DECIMALS);
+            //The nudge keeps something like 0.3 (really 0.29999...) from losing its last decimal
+            (quantity * scale + 1e-6).floor() / scale
+        } else {
+            quantity.floor()
+        }
+    }
+}
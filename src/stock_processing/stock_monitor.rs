@@ -1,34 +1,126 @@
-use crate::alpaca_api::{APIThreadReq, APIThreadRes};
-use crate::market_strategies::{
-    FibonacciRetracement, SingleMovingAverage, StockStrategy, StrategyOutput, SupportNResist,
-    TwoMovingAverages,
+use crate::bar_aggregator::{BarAggregator, Timeframe};
+use crate::broker::{
+    Bracket, Broker, BrokerOrder, BrokerPosition, OpenOrder, OrderRequest, OrderSide, OrderStatus,
+    OrderType, TimeInForce,
 };
+use crate::clock::Clock;
+use crate::market_strategies::registry::find_strategy;
+use crate::market_strategies::volume_filter::VolumeFilter;
+use crate::market_strategies::{Signal, StockStrategy, StrategyOutput};
+use crate::stock_processing::entry_order::EntryOrder;
+use crate::stock_processing::reconcile::ReconcilePolicy;
+use crate::stock_processing::sizing::Sizing;
+use crate::stock_processing::trailing_stop::TrailingStop;
 use anyhow::{Error, Result};
 use apca::data::v2::stream::Bar;
-use chrono::{Datelike, Timelike, Utc};
-use crossbeam_channel::{unbounded, Receiver, Sender};
+use chrono::{DateTime, Datelike, Timelike, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::ops::Neg;
 use std::sync::Arc;
-use std::sync::RwLock;
-use tracing::{error, info, warn};
+use tracing::{info, warn};
 
 pub struct StockMonitor {
     stock_strategy: Box<dyn StockStrategy + Send + Sync + 'static>,
-    backtest_mode: bool,
-    api_tx: Sender<(APIThreadReq, Sender<APIThreadRes>)>,
-    bought_stock: bool,
-    emergency_margin_limit: f64, //If the price falls above or bellow this threshold relative to what the stock was bought at it will be sold, meant for sudden crashes
-    bought_at: f64,
+    //The strategy's resolved strategy_params from the config, a saved strategy with other params isn't restored
+    strategy_params: Option<String>,
+    clock: Clock,
+    broker: Arc<dyn Broker + Send + Sync>,
+    //Shares held, negative when short, fractional with notional or percent of equity sizing
+    position: f64,
+    emergency_margin_limit: f64, //If the position loses more than this percent from what it was entered at it will be closed, meant for sudden crashes
+    entry_price: f64,
     pub symbol: String,
+    //Day the position was opened, long or short
     buy_time: i32,
     upper_limit: Option<f64>,
     intensity: u32,
-    how_much_bought: u32,
+    //How big a position is, intensity shares unless the stock sets a dollar amount or a percent of equity
+    sizing: Sizing,
+    //Sells with nothing held open a short instead of being ignored
+    allow_short: bool,
+    //Shares the last buy went without because there wasn't enough cash, picked up by the backtest to spot symbols competing for cash
+    cash_shortfall: Option<f64>,
+    volume_filter: Option<VolumeFilter>,
+    //The strategy runs on bars of this timeframe, the limits above are still checked on every minute
+    timeframe: Timeframe,
+    //One per timeframe the stock and its strategy use, not saved to the DB so a restart starts the bars being built over
+    aggregators: BTreeMap<Timeframe, BarAggregator>,
+    //Percent of the cash a buy can lose if it gets to the strategy's stop, caps the shares of strategies that give one
+    risk_per_trade: Option<f64>,
+    //Where the strategy said to get out of the position when it was bought
+    stop: Option<f64>,
+    target: Option<f64>,
+    //Closes the position once the price falls back far enough from the best close since entry
+    trailing_stop: Option<TrailingStop>,
+    //Highest close since the position was opened, lowest for shorts
+    high_water_mark: Option<f64>,
+    //Sends the emergency and upper limits (or the strategy's stop and target) with the entry so the broker holds them
+    bracket_orders: bool,
+    //IDs of the broker's exit orders for the position, the limits they cover aren't checked here while they're up
+    bracket_legs: Vec<String>,
+    //How positions are opened, closing is always a market order so the limits get out no matter what
+    entry_order: EntryOrder,
+    time_in_force: TimeInForce,
+    extended_hours: bool,
+    //An entry the broker took but hasn't filled yet
+    pending_entry: Option<PendingEntry>,
+    //The bot's own closing order until it fills
+    pending_exit: Option<PendingExit>,
+}
+
+//The position is opened once the broker fills this
+#[derive(Serialize, Deserialize, Clone)]
+struct PendingEntry {
+    id: String,
+    short: bool,
+    quantity: f64,
+    //Last close when it was placed
+    price: f64,
+    legs: Vec<String>,
+}
+
+//The position is gone once the broker fills this
+#[derive(Serialize, Deserialize, Clone)]
+struct PendingExit {
+    id: String,
+    //Position when it was placed
+    held: f64,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct SimplifiedDBMonitor {
+    bought_stock: bool,
+    buy_price: f64,
+    strat_bytes: Vec<u8>,
+    strat_name: String,
+    //The strategy_params strat_bytes were made with, missing from older entries
+    #[serde(default)]
+    strat_params: Option<String>,
+    buy_time: i32,
+    //Older entries saved whole shares as how_much
+    #[serde(alias = "how_much")]
+    quantity: f64,
+    //The position is quantity shares short instead of long
+    #[serde(default)]
+    short: bool,
+    #[serde(default)]
+    stop: Option<f64>,
+    #[serde(default)]
+    target: Option<f64>,
+    #[serde(default)]
+    high_water_mark: Option<f64>,
+    #[serde(default)]
+    bracket_legs: Vec<String>,
+    #[serde(default)]
+    pending_entry: Option<PendingEntry>,
+    #[serde(default)]
+    pending_exit: Option<PendingExit>,
+}
+
+//How the monitor was saved before it moved to JSON
+#[derive(Deserialize)]
+struct LegacyDBMonitor {
     bought_stock: bool,
     buy_price: f64,
     strat_bytes: Vec<u8>,
@@ -37,117 +129,330 @@ pub struct SimplifiedDBMonitor {
     how_much: u32,
 }
 
+impl SimplifiedDBMonitor {
+    ///Saved as JSON so new fields can get a default instead of making the old entries unreadable
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+
+    ///Reads both the JSON and the bincode that older versions of the bot saved
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if let Ok(monitor) = serde_json::from_slice(bytes) {
+            return Ok(monitor);
+        }
+        let legacy: LegacyDBMonitor = bincode::deserialize(bytes)
+            .map_err(|e| Error::msg(format!("Unreadable stock state in DB: {}", e)))?;
+        Ok(Self {
+            bought_stock: legacy.bought_stock,
+            buy_price: legacy.buy_price,
+            strat_bytes: legacy.strat_bytes,
+            strat_name: legacy.strat_name,
+            strat_params: None,
+            buy_time: legacy.buy_time,
+            quantity: legacy.how_much as f64,
+            short: false,
+            stop: None,
+            target: None,
+            high_water_mark: None,
+            bracket_legs: vec![],
+            pending_entry: None,
+            pending_exit: None,
+        })
+    }
+}
+
 impl StockMonitor {
     pub fn new(
         symbol: String,
-        api_tx: Sender<(APIThreadReq, Sender<APIThreadRes>)>,
+        broker: Arc<dyn Broker + Send + Sync>,
         backtest_mode: bool,
-        strategy: String,
+        strategy: Box<dyn StockStrategy + Send + Sync>,
         emergency_margin_limit: f64,
         upper_limit: Option<f64>,
         intensity: u32,
     ) -> Self {
-        //Select strat based on the config
-        let strat: Box<dyn StockStrategy + Send + Sync> = match &*strategy {
-            "Single Moving Average" => Box::new(SingleMovingAverage::new()),
-            "Two Moving Averages" => Box::new(TwoMovingAverages::new()),
-            "Support and Resist" => Box::new(SupportNResist::new()),
-            "Fibonacci" => Box::new(FibonacciRetracement::new()),
-            _ => {
-                error!("[{}] Unknown strategy", &symbol);
-                panic!("Unknown strategy set")
-            }
-        };
-
-        if backtest_mode {
+        //Backtests follow the time stamps of the bars they are fed instead of the wall clock
+        let clock = if backtest_mode {
             info!("[{}] Starting in backtest mode", &symbol);
-        }
+            Clock::Simulated(DateTime::<Utc>::MIN_UTC)
+        } else {
+            Clock::Wall
+        };
 
-        Self {
-            stock_strategy: strat,
-            backtest_mode,
-            api_tx,
-            bought_stock: false,
+        let mut monitor = Self {
+            stock_strategy: strategy,
+            strategy_params: None,
+            clock,
+            broker,
+            position: 0.0,
             emergency_margin_limit: emergency_margin_limit.neg(),
-            bought_at: 0.0,
+            entry_price: 0.0,
             symbol,
             buy_time: 0,
             upper_limit,
             intensity,
-            how_much_bought: 0,
+            sizing: Sizing::Shares,
+            allow_short: false,
+            cash_shortfall: None,
+            volume_filter: None,
+            timeframe: Timeframe::OneMinute,
+            aggregators: BTreeMap::new(),
+            risk_per_trade: None,
+            stop: None,
+            target: None,
+            trailing_stop: None,
+            high_water_mark: None,
+            bracket_orders: false,
+            bracket_legs: vec![],
+            entry_order: EntryOrder::Market,
+            time_in_force: TimeInForce::Day,
+            extended_hours: false,
+            pending_entry: None,
+            pending_exit: None,
+        };
+        monitor.reset_aggregators();
+        monitor
+    }
+
+    pub fn set_strategy_params(&mut self, params: String) {
+        self.strategy_params = Some(params);
+    }
+
+    pub fn set_timeframe(&mut self, timeframe: Timeframe) {
+        self.timeframe = timeframe;
+        self.reset_aggregators();
+    }
+
+    //Has to follow the strategy, a restored one can want other timeframes
+    fn reset_aggregators(&mut self) {
+        self.aggregators = [self.timeframe]
+            .into_iter()
+            .chain(self.stock_strategy.timeframes())
+            .map(|timeframe| (timeframe, BarAggregator::new(timeframe)))
+            .collect();
+    }
+
+    pub fn set_sizing(&mut self, sizing: Sizing) {
+        self.sizing = sizing;
+    }
+
+    pub fn set_bracket_orders(&mut self, bracket_orders: bool) {
+        self.bracket_orders = bracket_orders;
+    }
+
+    pub fn set_entry_order(
+        &mut self,
+        entry_order: EntryOrder,
+        time_in_force: TimeInForce,
+        extended_hours: bool,
+    ) {
+        self.entry_order = entry_order;
+        self.time_in_force = time_in_force;
+        self.extended_hours = extended_hours;
+    }
+
+    pub fn set_allow_short(&mut self, allow_short: bool) {
+        self.allow_short = allow_short;
+    }
+
+    pub fn set_risk_per_trade(&mut self, percent: f64) {
+        self.risk_per_trade = Some(percent);
+    }
+
+    pub fn set_trailing_stop(&mut self, trailing_stop: TrailingStop) {
+        self.trailing_stop = Some(trailing_stop);
+    }
+
+    //Entries on low volume bars get ignored, whatever the strategy
+    pub fn set_volume_filter(&mut self, filter: VolumeFilter) {
+        self.volume_filter = Some(filter);
+    }
+
+    //Feeds every finished bar to the strategy, the ones of the stock's timeframe give the signal
+    //Nothing to act on until a bar of the stock's timeframe finishes
+    fn run_strategy(&mut self, finished: Vec<(Timeframe, Bar)>) -> Result<Option<Signal>> {
+        let wanted = self.stock_strategy.timeframes();
+        for (timeframe, bar) in &finished {
+            if wanted.contains(timeframe) {
+                self.stock_strategy.run_timeframe(*timeframe, bar)?;
+            }
         }
+
+        self.stock_strategy.set_position(self.position);
+        let mut strat_result = None;
+        for (_, bar) in finished
+            .into_iter()
+            .filter(|(timeframe, _)| *timeframe == self.timeframe)
+        {
+            let mut signal = self.stock_strategy.run(&bar)?;
+            if let Some(filter) = &mut self.volume_filter {
+                filter.set_position(self.position);
+                let had_signal = !signal.is_hold();
+                signal = filter.apply(bar.volume as f64, signal);
+                if had_signal && signal.is_hold() {
+                    info!(
+                        "[{}]: Ignoring signal, volume of {} is too low",
+                        &self.symbol, bar.volume
+                    );
+                }
+            }
+            //When a gap finishes two bars at once only the newest one's signal is still worth acting on
+            strat_result = Some(signal);
+        }
+        Ok(strat_result)
     }
 
-    pub fn run(&mut self, assets: Arc<RwLock<f64>>, bar_data: Option<Bar>) -> Result<()> {
-        if self.backtest_mode {
-            info!("[{}] Starting backtest", &self.symbol);
-            self.run_backtest(assets)?;
-            return Ok(());
+    //Every bar the 1 minute bar finished, one per timeframe at most
+    fn aggregate(&mut self, bar_data: &Bar) -> Vec<(Timeframe, Bar)> {
+        self.aggregators
+            .iter_mut()
+            .flat_map(|(timeframe, aggregator)| {
+                aggregator
+                    .push(bar_data)
+                    .into_iter()
+                    .map(|bar| (*timeframe, bar))
+            })
+            .collect()
+    }
+
+    fn update_trailing_stop(&mut self, finished: &[(Timeframe, Bar)]) {
+        if let Some(trailing_stop) = &mut self.trailing_stop {
+            for (_, bar) in finished
+                .iter()
+                .filter(|(timeframe, _)| *timeframe == self.timeframe)
+            {
+                trailing_stop.update(bar);
+            }
         }
+    }
 
-        //Should only be none if in backtest mode
-        if bar_data.is_none() {
-            return Err(Error::msg("No bar data provided and not in backtest mode!"));
+    ///Runs one 1 minute bar through the aggregators, the strategy and the trailing stop without trading on it
+    ///Lets a fresh monitor start with its indicators ready, like the walk forward's out of sample windows
+    pub fn warm_up(&mut self, bar_data: Bar) -> Result<()> {
+        let finished = self.aggregate(&bar_data);
+        self.update_trailing_stop(&finished);
+        self.run_strategy(finished)?;
+        Ok(())
+    }
+
+    ///Runs one 1 minute bar through the aggregators, the strategy and the limits and acts on it, live trading and backtests both come through here
+    pub fn run(&mut self, bar_data: Bar) -> Result<()> {
+        self.clock.advance_to(bar_data.timestamp);
+        let finished = self.aggregate(&bar_data);
+
+        //The broker may have closed the position since the last bar
+        if !self.bracket_legs.is_empty() {
+            self.reconcile_legs();
+        }
+        if self.pending_entry.is_some() {
+            self.check_pending_entry();
+        }
+        if self.pending_exit.is_some() {
+            self.check_pending_exit();
         }
 
-        let bar_data = bar_data.unwrap();
+        let close: f64 = bar_data.close_price.to_f64().unwrap();
+        self.update_trailing_stop(&finished);
+        //Follows the price from the minute the position opened, even on the day it can't be closed yet
+        if self.position != 0.0 {
+            let short = self.position < 0.0;
+            self.high_water_mark = Some(match self.high_water_mark {
+                Some(mark) if short => mark.min(close),
+                Some(mark) => mark.max(close),
+                None => close,
+            });
+        }
 
         //If we have not advanced one day since we bought, dont run. We need to swing trade
         if self.same_trade_buy_day() {
             return Ok(());
         }
 
-        let strat_result = self.stock_strategy.run(&bar_data)?;
+        let strat_result = self.run_strategy(finished)?;
 
-        let close: f64 = bar_data.close_price.to_f64().unwrap();
+        if self.position != 0.0 {
+            let short = self.position < 0.0;
+            //How much the position made in percent, a short makes money when the price drops
+            let percentage = ((close - self.entry_price) / self.entry_price)
+                * 100.0
+                * if short { -1.0 } else { 1.0 };
+            let verb = if short { "Covered" } else { "Sold" };
+            //The broker's exit orders already cover the limits and the strategy's stop and target
+            let broker_side = !self.bracket_legs.is_empty();
+            let (stop, target) = if broker_side {
+                (None, None)
+            } else {
+                (self.stop, self.target)
+            };
 
-        //Create a return channel for when we make an API call
-        let (res_tx, res_rx) = unbounded();
-        if self.bought_stock {
-            let percentage = ((close - self.bought_at) / self.bought_at) * 100.0;
-
-            //check to see if price has dropped too much, if so sell
-            if percentage <= self.emergency_margin_limit {
-                self.api_tx
-                    .send((
-                        APIThreadReq::ApiSellStock {
-                            symbol: self.symbol.clone(),
-                            quantity: self.how_much_bought as usize,
-                        },
-                        res_tx,
-                    ))
-                    .unwrap();
-                if let Ok(APIThreadRes::ApiProcessed) = res_rx.recv() {
-                    self.bought_stock = false;
+            //check to see if the position lost too much, if so close it
+            if !broker_side && percentage <= self.emergency_margin_limit {
+                if self.close_position(close) {
                     warn!(
-                        "[{}]: Emergency margin triggered!!! Sold at : {}",
-                        &self.symbol, &close
+                        "[{}]: Emergency margin triggered!!! {} at : {}",
+                        &self.symbol, verb, &close
                     );
-                } else {
-                    info!("[{}]: Error from alpaca API", &self.symbol);
                 }
                 return Ok(());
             }
             //Unwrap is fine here, the evaluation to see if it exists happens first, allowing the program to back out if the unwrap will be dangerous
-            //Checks to see if we have hit the upper limit (set in config), if so, sell
-            if self.upper_limit.is_some() && percentage >= self.upper_limit.unwrap() {
-                self.api_tx
-                    .send((
-                        APIThreadReq::ApiSellStock {
-                            symbol: self.symbol.clone(),
-                            quantity: self.how_much_bought as usize,
-                        },
-                        res_tx,
-                    ))
-                    .unwrap();
-                if let Ok(APIThreadRes::ApiProcessed) = res_rx.recv() {
-                    self.bought_stock = false;
+            //Checks to see if we have hit the upper limit (set in config), if so, close
+            if !broker_side && self.upper_limit.is_some() && percentage >= self.upper_limit.unwrap()
+            {
+                if self.close_position(close) {
                     warn!(
-                        "[{}]: Upper bound triggered, Sold at : {}",
-                        &self.symbol, &close,
+                        "[{}]: Upper bound triggered, {} at : {}",
+                        &self.symbol, verb, &close,
                     );
+                }
+                return Ok(());
+            }
+
+            //Ratchets with the high water mark, a short's stop sits above its lowest close
+            if let Some(stop) = self.trailing_stop_price(short).filter(|stop| {
+                if short {
+                    close >= *stop
                 } else {
-                    info!("[{}]: Error from alpaca API", &self.symbol);
+                    close <= *stop
+                }
+            }) {
+                if self.close_position(close) {
+                    warn!(
+                        "[{}]: Trailing stop of {} hit, {} at : {}",
+                        &self.symbol, stop, verb, &close
+                    );
+                }
+                return Ok(());
+            }
+
+            //The strategy said where the trade would be wrong or done when it was entered, both are mirrored for shorts
+            if let Some(stop) = stop.filter(|stop| {
+                if short {
+                    close >= *stop
+                } else {
+                    close <= *stop
+                }
+            }) {
+                if self.close_position(close) {
+                    warn!(
+                        "[{}]: Strategy stop of {} hit, {} at : {}",
+                        &self.symbol, stop, verb, &close
+                    );
+                }
+                return Ok(());
+            }
+            if let Some(target) = target.filter(|target| {
+                if short {
+                    close <= *target
+                } else {
+                    close >= *target
+                }
+            }) {
+                if self.close_position(close) {
+                    info!(
+                        "[{}]: Strategy target of {} hit, {} at : {}",
+                        &self.symbol, target, verb, &close
+                    );
                 }
                 return Ok(());
             }
@@ -155,17 +460,32 @@ impl StockMonitor {
             //It's friday, liquidate assets if it wont trigger PDT.
             if self.friday_near_end_of_trading_day() && !self.same_trade_buy_day() {
                 info!("Nearing end of day friday, liquidating assets");
-                self.sell(close, res_tx, res_rx);
+                self.close_position(close);
                 return Ok(());
             }
         }
 
-        match strat_result {
+        let signal = strat_result.unwrap_or_else(Signal::hold);
+        if self.pending_entry.is_some() {
+            self.signal_while_pending(&signal);
+            return Ok(());
+        }
+        match signal.direction {
+            //A buy covers a short, a sell closes a long, and with nothing held they open a position
+            StrategyOutput::Buy if self.position < 0.0 => {
+                self.close_position(close);
+            }
             StrategyOutput::Buy => {
-                self.buy(close, assets, res_tx, res_rx);
+                self.open_position(OrderSide::Buy, close, &signal);
+            }
+            StrategyOutput::Sell if self.position > 0.0 => {
+                self.close_position(close);
+            }
+            StrategyOutput::Sell if self.allow_short => {
+                self.open_position(OrderSide::Sell, close, &signal);
             }
             StrategyOutput::Sell => {
-                self.sell(close, res_tx, res_rx);
+                info!("[{}]: Cannot sell, dont have stock", &self.symbol);
             }
             StrategyOutput::Hold => {
                 info!("[{}]: Holding...", &self.symbol);
@@ -175,202 +495,595 @@ impl StockMonitor {
         Ok(())
     }
 
-    fn sell(
-        &mut self,
-        current_price: f64,
-        res_tx: Sender<APIThreadRes>,
-        res_rx: Receiver<APIThreadRes>,
-    ) {
-        if !self.bought_stock {
-            info!("[{}]: Cannot sell, dont have stock", &self.symbol);
+    fn trailing_stop_price(&self, short: bool) -> Option<f64> {
+        let mark = self.high_water_mark?;
+        let distance = self.trailing_stop.as_ref()?.distance(mark)?;
+        Some(if short {
+            mark + distance
+        } else {
+            mark - distance
+        })
+    }
+
+    //Follows the entry order, the position grows with every share the broker fills at the average price it filled them at
+    fn check_pending_entry(&mut self) {
+        let Some(entry) = self.pending_entry.clone() else {
+            return;
+        };
+        let order = match self.broker.order(&entry.id) {
+            Ok(order) => order,
+            Err(e) => {
+                info!("[{}]: Error from broker: {}", &self.symbol, e);
+                return;
+            }
+        };
+        if order.id != entry.id {
+            self.log_replaced(&entry.id, &order.id);
+            if let Some(pending) = &mut self.pending_entry {
+                pending.id = order.id.clone();
+            }
+        }
+
+        if order.filled_quantity > 0.0 {
+            //First fill, the position starts now
+            if self.position == 0.0 {
+                self.buy_time = self.clock.now().num_days_from_ce();
+                self.bracket_legs = entry.legs.clone();
+            }
+            self.entry_price = order.filled_avg_price.unwrap_or(entry.price);
+            self.position = if entry.short {
+                -order.filled_quantity
+            } else {
+                order.filled_quantity
+            };
+            self.high_water_mark = Some(match self.high_water_mark {
+                Some(mark) if entry.short => mark.min(self.entry_price),
+                Some(mark) => mark.max(self.entry_price),
+                None => self.entry_price,
+            });
+        }
+
+        match order.status {
+            OrderStatus::Open | OrderStatus::PartiallyFilled => {}
+            OrderStatus::Filled => {
+                self.pending_entry = None;
+                let total_calc: f64 = self.entry_price * order.filled_quantity;
+                info!(
+                    "[{}]: {} {} shares at : {} each, total of: {}",
+                    &self.symbol,
+                    if entry.short { "Shorted" } else { "Bought" },
+                    order.filled_quantity,
+                    &self.entry_price,
+                    total_calc
+                );
+                info!("Stock Watcher suspended until next day");
+            }
+            OrderStatus::Canceled | OrderStatus::Rejected => {
+                self.pending_entry = None;
+                if order.filled_quantity > 0.0 {
+                    info!(
+                        "[{}]: Entry order {} was {:?} after filling {} of {} shares at : {}, keeping them",
+                        &self.symbol,
+                        &order.id,
+                        order.status,
+                        order.filled_quantity,
+                        entry.quantity,
+                        &self.entry_price
+                    );
+                } else {
+                    info!(
+                        "[{}]: Entry order {} was {:?} without filling",
+                        &self.symbol, &order.id, order.status
+                    );
+                    self.stop = None;
+                    self.target = None;
+                }
+            }
+        }
+    }
+
+    //Takes back what's left of the entry order, false until the broker says it's done
+    fn cancel_pending_entry(&mut self) -> bool {
+        let Some(entry) = &self.pending_entry else {
+            return true;
+        };
+        let order_id = entry.id.clone();
+        if let Err(e) = self.broker.cancel_order(&order_id) {
+            info!(
+                "[{}]: Could not cancel entry order {}: {}",
+                &self.symbol, &order_id, e
+            );
+        }
+        self.check_pending_entry();
+        self.pending_entry.is_none()
+    }
+
+    //A signal the other way takes the waiting entry back, the same way it just keeps waiting
+    fn signal_while_pending(&mut self, signal: &Signal) {
+        let Some(entry) = &self.pending_entry else {
+            return;
+        };
+        let against = match signal.direction {
+            StrategyOutput::Buy => entry.short,
+            StrategyOutput::Sell => !entry.short,
+            StrategyOutput::Hold => false,
+        };
+        let order_id = entry.id.clone();
+        if !against {
+            info!(
+                "[{}]: Waiting on entry order {} to fill",
+                &self.symbol, &order_id
+            );
             return;
         }
+        if self.cancel_pending_entry() {
+            info!(
+                "[{}]: Canceled entry order {}, the strategy turned the other way",
+                &self.symbol, &order_id
+            );
+        }
+    }
+
+    //Follows the bot's own closing order, the position shrinks with every share filled
+    fn check_pending_exit(&mut self) {
+        let Some(exit) = self.pending_exit.clone() else {
+            return;
+        };
+        let order = match self.broker.order(&exit.id) {
+            Ok(order) => order,
+            Err(e) => {
+                info!("[{}]: Error from broker: {}", &self.symbol, e);
+                return;
+            }
+        };
+        if order.id != exit.id {
+            self.log_replaced(&exit.id, &order.id);
+            if let Some(pending) = &mut self.pending_exit {
+                pending.id = order.id.clone();
+            }
+        }
+        let verb = if exit.held < 0.0 { "covered" } else { "sold" };
+        self.position = (exit.held.abs() - order.filled_quantity).max(0.0) * exit.held.signum();
+
+        match order.status {
+            OrderStatus::Open | OrderStatus::PartiallyFilled => {}
+            OrderStatus::Filled => {
+                info!(
+                    "[{}]: {} {} shares at : {}",
+                    &self.symbol,
+                    verb,
+                    order.filled_quantity,
+                    order.filled_avg_price.unwrap_or_default()
+                );
+                self.clear_position();
+            }
+            OrderStatus::Canceled | OrderStatus::Rejected => {
+                self.pending_exit = None;
+                warn!(
+                    "[{}]: Exit order {} was {:?} after it {} {} of {} shares",
+                    &self.symbol,
+                    &order.id,
+                    order.status,
+                    verb,
+                    order.filled_quantity,
+                    exit.held.abs()
+                );
+                if self.position == 0.0 {
+                    self.clear_position();
+                }
+            }
+        }
+    }
+
+    //An order changed at the broker comes back under the ID of the order that replaced it
+    fn log_replaced(&self, old_id: &str, new_id: &str) {
+        info!(
+            "[{}]: Order {} was replaced by {}, following that one now",
+            &self.symbol, old_id, new_id
+        );
+    }
+
+    //Checks the bracket's exit orders, if one filled the broker closed the position
+    fn reconcile_legs(&mut self) {
+        let mut waiting = false;
+        for leg in self.bracket_legs.clone() {
+            let order = match self.broker.order(&leg) {
+                Ok(order) => order,
+                Err(e) => {
+                    info!("[{}]: Error from broker: {}", &self.symbol, e);
+                    return;
+                }
+            };
+            if order.id != leg {
+                self.log_replaced(&leg, &order.id);
+                if let Some(kept) = self.bracket_legs.iter_mut().find(|kept| **kept == leg) {
+                    *kept = order.id.clone();
+                }
+            }
+            if order.status == OrderStatus::Filled {
+                self.leg_filled(&order);
+                return;
+            }
+            waiting |= matches!(
+                order.status,
+                OrderStatus::Open | OrderStatus::PartiallyFilled
+            );
+        }
+        if !waiting {
+            warn!(
+                "[{}]: Bracket orders were canceled at the broker, checking the limits here again",
+                &self.symbol
+            );
+            self.bracket_legs.clear();
+        }
+    }
+
+    fn leg_filled(&mut self, order: &BrokerOrder) {
+        info!(
+            "[{}]: Bracket order {} {} {} shares at : {}",
+            &self.symbol,
+            &order.id,
+            if self.position < 0.0 {
+                "covered"
+            } else {
+                "sold"
+            },
+            order.filled_quantity,
+            order.filled_avg_price.unwrap_or_default()
+        );
+        self.clear_position();
+    }
 
-        //Send sell request to API processing thread
-        self.api_tx
-            .send((
-                APIThreadReq::ApiSellStock {
-                    symbol: self.symbol.clone(),
-                    quantity: self.how_much_bought as usize,
-                },
-                res_tx,
-            ))
-            .unwrap();
-
-        //Make sure sell request is processed before updating stock state
-        if let Ok(APIThreadRes::ApiProcessed) = res_rx.recv() {
-            self.bought_stock = false;
+    //Takes the bracket's exit orders down before the position is closed here, false if they couldn't be or one of them already closed it
+    fn cancel_legs(&mut self) -> bool {
+        for leg in self.bracket_legs.clone() {
+            if self.broker.cancel_order(&leg).is_ok() {
+                continue;
+            }
+            //Canceling one leg can take the other with it
+            match self.broker.order(&leg) {
+                Ok(order)
+                    if matches!(order.status, OrderStatus::Canceled | OrderStatus::Rejected) => {}
+                Ok(order) if order.status == OrderStatus::Filled => {
+                    self.leg_filled(&order);
+                    return false;
+                }
+                Ok(order) => {
+                    info!(
+                        "[{}]: Could not cancel bracket order {}",
+                        &self.symbol, &order.id
+                    );
+                    return false;
+                }
+                Err(e) => {
+                    info!("[{}]: Error from broker: {}", &self.symbol, e);
+                    return false;
+                }
+            }
+        }
+        self.bracket_legs.clear();
+        true
+    }
+
+    fn clear_position(&mut self) {
+        self.position = 0.0;
+        self.pending_exit = None;
+        self.stop = None;
+        self.target = None;
+        self.high_water_mark = None;
+        self.bracket_legs.clear();
+    }
+
+    //Exit prices for the broker to hold, the tighter of the limit from the config and the strategy's
+    fn bracket(&self, short: bool, price: f64) -> Bracket {
+        let direction = if short { -1.0 } else { 1.0 };
+        let emergency = price * (1.0 + direction * self.emergency_margin_limit / 100.0);
+        let upper = self
+            .upper_limit
+            .map(|limit| price * (1.0 + direction * limit / 100.0));
+        let tighter = |a: f64, b: f64, lower: bool| if lower { a.min(b) } else { a.max(b) };
+        Bracket {
+            take_profit: match (upper, self.target) {
+                (Some(upper), Some(target)) => Some(tighter(upper, target, !short)),
+                (upper, target) => upper.or(target),
+            },
+            stop_loss: Some(match self.stop {
+                Some(stop) => tighter(emergency, stop, short),
+                None => emergency,
+            }),
+        }
+    }
+
+    //Sells a long or buys back a short, returns true if the broker accepted the order
+    //The position is only gone once the order fills
+    fn close_position(&mut self, current_price: f64) -> bool {
+        if self.position == 0.0 {
+            info!("[{}]: Cannot sell, dont have stock", &self.symbol);
+            return false;
+        }
+        if let Some(exit) = &self.pending_exit {
             info!(
-                "[{}]: sold {} shares at : {}",
-                &self.symbol, self.how_much_bought, &current_price
+                "[{}]: Waiting on exit order {} to fill",
+                &self.symbol, &exit.id
             );
+            return false;
+        }
+        //Whatever is left of a partly filled entry would add to the position after it's closed
+        if !self.cancel_pending_entry() {
+            return false;
+        }
+        if !self.bracket_legs.is_empty() && !self.cancel_legs() {
+            return false;
+        }
+        let side = if self.position > 0.0 {
+            OrderSide::Sell
         } else {
-            info!("[{}]: Error from alpaca API", &self.symbol);
+            OrderSide::Buy
+        };
+
+        match self.broker.submit_order(OrderRequest {
+            symbol: self.symbol.clone(),
+            side,
+            quantity: self.position.abs(),
+            reference_price: current_price,
+            order_type: OrderType::Market,
+            time_in_force: TimeInForce::Day,
+            extended_hours: false,
+            bracket: None,
+        }) {
+            Ok(submitted) => {
+                self.pending_exit = Some(PendingExit {
+                    id: submitted.id,
+                    held: self.position,
+                });
+                self.check_pending_exit();
+                true
+            }
+            Err(e) => {
+                info!("[{}]: Error from broker: {}", &self.symbol, e);
+                false
+            }
         }
     }
 
-    fn buy(
-        &mut self,
-        current_price: f64,
-        usable_assets: Arc<RwLock<f64>>,
-        res_tx: Sender<APIThreadRes>,
-        res_rx: Receiver<APIThreadRes>,
-    ) {
-        if self.bought_stock {
-            info!("[{}]: Cannot buy, already bought stock", &self.symbol);
+    //Shares a signal asks for, the signal scales the full size and its stop can cap it to risk_per_trade of the cash
+    fn shares_wanted(
+        &self,
+        short: bool,
+        price: f64,
+        cash: f64,
+        full_size: f64,
+        signal: &Signal,
+    ) -> f64 {
+        let fractional = self.fractional(short);
+        let shares = full_size * signal.size_fraction();
+        let shares = if self.sizing.fractional() {
+            Sizing::round_down(shares, fractional)
+        } else {
+            shares.round()
+        };
+        //What one share loses if the stop is hit
+        let risk_per_share = signal
+            .stop
+            .map(|stop| if short { stop - price } else { price - stop });
+        match (self.risk_per_trade, risk_per_share) {
+            (Some(risk), Some(per_share)) if per_share > 0.0 => shares.min(Sizing::round_down(
+                cash * risk / 100.0 / per_share,
+                fractional,
+            )),
+            _ => shares,
+        }
+    }
+
+    //Alpaca only shorts whole shares
+    fn fractional(&self, short: bool) -> bool {
+        self.sizing.fractional() && !short
+    }
+
+    //Buys, or sells short, with nothing held
+    fn open_position(&mut self, side: OrderSide, current_price: f64, signal: &Signal) {
+        let short = side == OrderSide::Sell;
+        let action = if short { "short" } else { "buy" };
+        if self.position != 0.0 {
+            info!(
+                "[{}]: Cannot {}, already {}",
+                &self.symbol,
+                action,
+                if short { "short" } else { "bought stock" }
+            );
             return;
         }
-        let total_intensity: u32 = {
-            //Calculate how many full shares we can buy
-            let how_many_possible = (*usable_assets.read().unwrap() / current_price).floor() as u32;
-            //If the allocated amount of shares is less than or equal to max possible (IE we're allowed to buy 5 but have the ability to buy 10)
-            //Just return the set number
-            if self.intensity <= how_many_possible {
-                self.intensity
+        let usable_assets = match self.broker.cash() {
+            Ok(cash) => cash,
+            Err(e) => {
+                info!("[{}]: Error from broker: {}", &self.symbol, e);
+                return;
             }
-            //Else, we cant buy the allocated amount shares, just buy as much as we can
+        };
+        let full_size = match self.sizing.dollars(|| self.broker.equity()) {
+            Ok(Some(dollars)) => dollars / current_price,
+            Ok(None) => self.intensity as f64,
+            Err(e) => {
+                info!("[{}]: Error from broker: {}", &self.symbol, e);
+                return;
+            }
+        };
+        let wanted = self.shares_wanted(short, current_price, usable_assets, full_size, signal);
+        if wanted <= 0.0 {
+            info!(
+                "[{}]: Cannot {}, signal of confidence {} is too small for a share",
+                &self.symbol, action, signal.confidence
+            );
+            return;
+        }
+        let total_intensity: f64 = {
+            //Calculate how many shares we can buy, shorts are held to the same cash so they can be covered
+            let how_many_possible =
+                Sizing::round_down(usable_assets / current_price, self.fractional(short));
+            //If the wanted amount of shares is less than or equal to max possible (IE we want 5 but have the ability to buy 10)
+            //Just return the wanted number
+            if wanted <= how_many_possible {
+                wanted
+            }
+            //Else, we cant buy the wanted amount shares, just buy as much as we can
             else {
+                self.cash_shortfall = Some(wanted - how_many_possible);
                 how_many_possible
             }
         };
 
         //Check to see if we can afford to buy
-        if total_intensity == 0 {
-            info!("[{}]: Cannot buy, not enough money available", &self.symbol);
+        if total_intensity <= 0.0 {
+            info!(
+                "[{}]: Cannot {}, not enough money available",
+                &self.symbol, action
+            );
             return;
-        } else {
-            //if so send buy request
-            self.api_tx
-                .send((
-                    APIThreadReq::ApiBuyStock {
-                        symbol: self.symbol.clone(),
-                        quantity: total_intensity as usize,
-                    },
-                    res_tx,
-                ))
-                .unwrap();
+        }
 
-            //Make sure buy request is accepted before updating stock state
-            if let Ok(APIThreadRes::ApiProcessed) = res_rx.recv() {
-                self.bought_at = current_price;
-                self.bought_stock = true;
-                self.buy_time = Utc::now().num_days_from_ce();
-                self.how_much_bought = total_intensity;
-                let total_calc: f64 = current_price * total_intensity as f64;
-                info!(
-                    "[{}]: Bought {} shares at : {} each, total of: {}",
-                    &self.symbol, total_intensity, &self.bought_at, total_calc
-                );
-                info!("Stock Watcher suspended until next day");
+        //A stop past the price or a target short of it would close the position right away
+        self.stop = signal.stop.filter(|stop| {
+            if short {
+                *stop > current_price
             } else {
-                info!("[{}]: Error from alpaca API", &self.symbol);
-            }
-        }
-    }
-
-    ///testing mode that uses files to run algorithms
-    fn run_backtest(&mut self, assets: Arc<RwLock<f64>>) -> Result<()> {
-        let mut money_made: f64 = 0.0;
-        let mut reader =
-            csv::Reader::from_path(format!("./backtest_data/{}.csv", &self.symbol)).unwrap();
-        for record in reader.deserialize() {
-            let (_, open, high, low, close, volume): (String, f64, f64, f64, f64, f64) =
-                record.unwrap();
-
-            let strat_result = self
-                .stock_strategy
-                .run_backtest(open, close, high, low, volume);
-            //check to see if price has dropped too much
-            if self.bought_stock {
-                let percentage = ((close - self.bought_at) / self.bought_at) * 100.0;
-                if percentage < self.emergency_margin_limit {
-                    *assets.write().unwrap() += close;
-                    money_made += close;
-                    self.bought_stock = false;
-                    //warn!(
-                    //"[{}]: Emergency margin triggered!!! Sold at : {}",
-                    //&self.symbol,
-                    // &data.close.unwrap()
-                    //);
-                    continue;
-                }
+                *stop < current_price
             }
+        });
+        self.target = signal.target.filter(|target| {
+            if short {
+                *target < current_price
+            } else {
+                *target > current_price
+            }
+        });
+        let bracket = self
+            .bracket_orders
+            .then(|| self.bracket(short, current_price));
+        let order_type = self.entry_order.order_type(side, current_price);
+        //A bracket's legs have to outlast the session
+        let time_in_force = if bracket.is_some() {
+            TimeInForce::Gtc
+        } else {
+            self.time_in_force
+        };
 
-            match strat_result {
-                StrategyOutput::Buy => {
-                    if self.bought_stock {
-                        info!("[{}]: Cannot buy, already bought stock", &self.symbol);
-                        continue;
-                    }
-                    if self.is_friday() {
-                        info!("[{}]: Cannot buy, end of market week", &self.symbol);
-                        continue;
-                    }
-                    let close: f64 = close;
-                    let total_intensity: u32 = {
-                        //Calculate how many full shares we can buy
-                        let how_many_possible = (*assets.read().unwrap() / close).floor() as u32;
-                        //If the allocated amount of shares is less than or equal to max possible (IE we're allowed to buy 5 but have the ability to buy 10)
-                        //Just return the set number
-                        if self.intensity <= how_many_possible {
-                            self.intensity
-                        }
-                        //Else, we cant buy the allocated amount shares, just buy as much as we can
-                        else {
-                            how_many_possible
-                        }
-                    };
-
-                    if total_intensity == 0 {
-                        //  info!("[{}]: Cannot buy, not enough money available", &self.symbol);
-                        continue;
-                    } else {
-                        money_made -= close * total_intensity as f64;
-                        *assets.write().unwrap() -= close * total_intensity as f64;
-                        self.bought_at = close;
-                        self.bought_stock = true;
-                        self.how_much_bought = total_intensity;
-                        let total_calc: f64 = close * total_intensity as f64;
-                        info!(
-                            "[{}]: Bought {} shares at : {} each, total of: {}",
-                            &self.symbol, total_intensity, &self.bought_at, total_calc
-                        );
-                    }
-                }
-                StrategyOutput::Sell => {
-                    if !self.bought_stock {
-                        info!("[{}]: Cannot sell, dont have stock", &self.symbol);
-                        continue;
-                    }
-                    money_made += close * self.how_much_bought as f64;
-                    *assets.write().unwrap() += close * self.how_much_bought as f64;
-                    self.bought_stock = false;
+        //Make sure the order is accepted before updating stock state
+        match self.broker.submit_order(OrderRequest {
+            symbol: self.symbol.clone(),
+            side,
+            quantity: total_intensity,
+            reference_price: current_price,
+            order_type,
+            time_in_force,
+            extended_hours: self.extended_hours,
+            bracket,
+        }) {
+            Ok(submitted) => {
+                let entry_id = submitted.id.clone();
+                self.pending_entry = Some(PendingEntry {
+                    id: submitted.id,
+                    short,
+                    quantity: total_intensity,
+                    price: current_price,
+                    legs: submitted.legs,
+                });
+                //Nothing is held until the broker says the order filled
+                self.check_pending_entry();
+                if self.pending_entry.is_some() {
                     info!(
-                        "[{}]: sold at at : {} with a total payout of {}",
-                        &self.symbol,
-                        &close,
-                        close * self.how_much_bought as f64
+                        "[{}]: Placed {:?} entry order {} for {} shares, waiting for it to fill",
+                        &self.symbol, order_type, &entry_id, total_intensity
                     );
                 }
-                StrategyOutput::Hold => {
-                    //info!("[{}]: Holding...", &self.symbol);
-                }
+            }
+            Err(e) => {
+                self.stop = None;
+                self.target = None;
+                info!("[{}]: Error from broker: {}", &self.symbol, e);
             }
         }
-        info!("[{}] profit made: {}", &self.symbol, money_made);
-        Ok(())
     }
-    fn is_friday(&self) -> bool {
-        let now = Utc::now();
-        now.weekday().num_days_from_monday() >= 4
+
+    //Startup check against the broker, follows the saved orders first so fills that came in while the bot was down count
+    //Returns what still doesn't add up, trusting the broker also makes the position match it
+    pub fn reconcile(
+        &mut self,
+        held: Option<&BrokerPosition>,
+        open_orders: &[&OpenOrder],
+        policy: ReconcilePolicy,
+    ) -> Vec<String> {
+        if !self.bracket_legs.is_empty() {
+            self.reconcile_legs();
+        }
+        if self.pending_entry.is_some() {
+            self.check_pending_entry();
+        }
+        if self.pending_exit.is_some() {
+            self.check_pending_exit();
+        }
+
+        let mut discrepancies = vec![];
+        for order in open_orders {
+            let known = self
+                .pending_entry
+                .as_ref()
+                .is_some_and(|entry| entry.id == order.id)
+                || self
+                    .pending_exit
+                    .as_ref()
+                    .is_some_and(|exit| exit.id == order.id)
+                || self.bracket_legs.contains(&order.id);
+            if !known {
+                discrepancies.push(format!(
+                    "{:?} order {} at the broker wasn't placed by the bot",
+                    order.side, &order.id
+                ));
+            }
+        }
+
+        let broker_quantity = held.map_or(0.0, |position| position.quantity);
+        if (broker_quantity - self.position).abs() > 1e-9 {
+            discrepancies.push(format!(
+                "DB holds {} shares, the broker holds {}",
+                self.position, broker_quantity
+            ));
+            if policy == ReconcilePolicy::TrustBroker {
+                self.adopt_position(held);
+            }
+        }
+        discrepancies
+    }
+
+    //Takes what the broker holds as the position, the limits work off its average entry price
+    fn adopt_position(&mut self, held: Option<&BrokerPosition>) {
+        let Some(held) = held.filter(|position| position.quantity != 0.0) else {
+            self.clear_position();
+            return;
+        };
+        //A position on the other side (or one the DB didn't know about) starts over
+        if self.position * held.quantity <= 0.0 {
+            self.clear_position();
+            //It's not known when it was opened, counted as before today so the limits watch it right away
+            self.buy_time = self.clock.now().num_days_from_ce() - 1;
+        }
+        self.position = held.quantity;
+        self.entry_price = held.average_entry_price;
+        if self.high_water_mark.is_none() {
+            self.high_water_mark = Some(self.entry_price);
+        }
+    }
+
+    //Returns and clears how many shares the last buy couldn't afford
+    pub fn take_cash_shortfall(&mut self) -> Option<f64> {
+        self.cash_shortfall.take()
     }
 
     fn same_trade_buy_day(&self) -> bool {
-        self.bought_stock && self.buy_time == Utc::now().num_days_from_ce()
+        self.position != 0.0 && self.buy_time == self.clock.now().num_days_from_ce()
     }
 
     fn friday_near_end_of_trading_day(&self) -> bool {
-        let now = Utc::now();
+        let now = self.clock.now();
         let is_friday: bool = now.weekday().num_days_from_monday() >= 4;
         let is_near_end: bool = now.hour() >= 18;
 
@@ -381,50 +1094,164 @@ impl StockMonitor {
     pub fn save_state(&self) -> SimplifiedDBMonitor {
         let strat_data = &self.stock_strategy.save_state();
         SimplifiedDBMonitor {
-            bought_stock: self.bought_stock,
-            buy_price: self.bought_at,
+            bought_stock: self.position != 0.0,
+            buy_price: self.entry_price,
             strat_bytes: strat_data.0.to_vec(),
             strat_name: strat_data.1.to_string(),
+            strat_params: self.strategy_params.clone(),
             buy_time: self.buy_time,
-            how_much: self.how_much_bought,
+            quantity: self.position.abs(),
+            short: self.position < 0.0,
+            stop: self.stop,
+            target: self.target,
+            high_water_mark: self.high_water_mark,
+            bracket_legs: self.bracket_legs.clone(),
+            pending_entry: self.pending_entry.clone(),
+            pending_exit: self.pending_exit.clone(),
         }
     }
 
     //Ran after the creation of a stock, sets the values in the monitor according to what's in the DB
     pub fn set_state(&mut self, simple_mon: SimplifiedDBMonitor) {
-        self.bought_stock = simple_mon.bought_stock;
-        self.bought_at = simple_mon.buy_price;
-        let strat: Box<dyn StockStrategy + Send + Sync> = match &*simple_mon.strat_name {
-            "Single Moving Average" => Box::new(
-                bincode::deserialize::<SingleMovingAverage>(&simple_mon.strat_bytes).unwrap(),
-            ),
-            "Two Moving Averages" => Box::new(
-                bincode::deserialize::<TwoMovingAverages>(&simple_mon.strat_bytes).unwrap(),
-            ),
-            "Support and Resist" => {
-                Box::new(bincode::deserialize::<SupportNResist>(&simple_mon.strat_bytes).unwrap())
-            }
-            "Fibonacci" => Box::new(
-                bincode::deserialize::<FibonacciRetracement>(&simple_mon.strat_bytes).unwrap(),
-            ),
-            _ => {
-                error!("[{}] Unknown strategy", &self.symbol);
-                panic!("Unknown strategy selected")
-            }
-        };
+        self.entry_price = simple_mon.buy_price;
+        self.stop = simple_mon.stop;
+        self.target = simple_mon.target;
+        self.high_water_mark = simple_mon.high_water_mark;
+        self.bracket_legs = simple_mon.bracket_legs;
+        self.pending_entry = simple_mon.pending_entry;
+        self.pending_exit = simple_mon.pending_exit;
         //Self explanitor, if the current strategy and the one in the DB are the same, simply replace, else ignore the DB
-        if self.stock_strategy.save_state().1 == simple_mon.strat_name {
-            self.stock_strategy = strat;
-        } else {
+        if self.stock_strategy.save_state().1 != simple_mon.strat_name {
             info!("New strategy detected from config, ignoring old strategy in DB")
+        } else if simple_mon.strat_params != self.strategy_params {
+            //The saved strategy would keep running with the params it was saved with
+            warn!(
+                "[{}] strategy_params changed in config (or weren't saved), ignoring old strategy in DB",
+                &self.symbol
+            );
+        } else {
+            match find_strategy(&simple_mon.strat_name)
+                .and_then(|entry| Ok(entry.restore(&simple_mon.strat_bytes)?))
+            {
+                Ok(strat) => {
+                    self.stock_strategy = strat;
+                    self.reset_aggregators();
+                }
+                //Happens when a strategy's saved layout changes between versions, it has to warm up again
+                Err(e) => warn!(
+                    "[{}] Could not load strategy state from DB, starting it fresh: {}",
+                    &self.symbol, e
+                ),
+            }
         }
         self.buy_time = simple_mon.buy_time;
         //To fix a minor error that happened before, leaving here just in cas
-        if simple_mon.how_much == 0 && simple_mon.bought_stock {
+        let shares = if simple_mon.quantity == 0.0 && simple_mon.bought_stock {
             info!("Error detected, intensity is at 0 when bought stock is true, correcting");
-            self.how_much_bought = 1;
+            1.0
         } else {
-            self.how_much_bought = simple_mon.how_much;
+            simple_mon.quantity
+        };
+        self.position = match (simple_mon.bought_stock, simple_mon.short) {
+            (false, _) => 0.0,
+            (true, false) => shares,
+            (true, true) => -shares,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backtesting::fill_model::FillModel;
+    use crate::broker::SimulatedBroker;
+    use crate::market_strategies::registry::{build_strategy, resolved_params};
+    use chrono::TimeZone;
+    use num_decimal::Num;
+    use std::sync::RwLock;
+
+    const RSI: &str = "Relative Strength Index";
+
+    fn monitor(period: i64) -> StockMonitor {
+        let mut params = find_strategy(RSI).unwrap().defaults().unwrap();
+        params
+            .as_table_mut()
+            .unwrap()
+            .insert("period".to_string(), toml::Value::Integer(period));
+        let broker = Arc::new(SimulatedBroker::new(
+            Arc::new(RwLock::new(1000.0)),
+            FillModel::default(),
+        ));
+        let mut monitor = StockMonitor::new(
+            "AAPL".to_string(),
+            broker,
+            true,
+            build_strategy(RSI, Some(&params)).unwrap(),
+            5.0,
+            None,
+            1,
+        );
+        monitor.set_strategy_params(resolved_params(RSI, Some(&params)).unwrap());
+        monitor
+    }
+
+    fn warmed_up(period: i64) -> StockMonitor {
+        let mut monitor = monitor(period);
+        for minute in 0..30 {
+            let price = Num::new(10_000 + (minute % 4) * 100 - minute * 10, 100);
+            monitor
+                .warm_up(Bar {
+                    symbol: "AAPL".to_string(),
+                    open_price: price.clone(),
+                    high_price: price.clone(),
+                    low_price: price.clone(),
+                    close_price: price,
+                    volume: 1000,
+                    timestamp: Utc
+                        .with_ymd_and_hms(2022, 6, 1, 14, minute as u32, 0)
+                        .unwrap(),
+                })
+                .unwrap();
         }
+        monitor
+    }
+
+    //The strategy bytes a monitor ends up with after loading another monitor's saved state
+    fn restored_into(saved: &StockMonitor, mut fresh: StockMonitor) -> Vec<u8> {
+        let state = SimplifiedDBMonitor::from_bytes(&saved.save_state().to_bytes()).unwrap();
+        fresh.set_state(state);
+        fresh.save_state().strat_bytes
+    }
+
+    #[test]
+    fn saved_strategy_is_restored_when_the_params_match() {
+        let saved = warmed_up(5);
+        assert_ne!(
+            saved.save_state().strat_bytes,
+            monitor(5).save_state().strat_bytes
+        );
+        assert_eq!(
+            restored_into(&saved, monitor(5)),
+            saved.save_state().strat_bytes
+        );
+    }
+
+    #[test]
+    fn saved_strategy_is_dropped_when_the_params_changed() {
+        let saved = warmed_up(5);
+        assert_eq!(
+            restored_into(&saved, monitor(6)),
+            monitor(6).save_state().strat_bytes
+        );
+    }
+
+    #[test]
+    fn saved_strategy_without_params_is_dropped() {
+        let mut saved = warmed_up(5);
+        saved.strategy_params = None;
+        assert_eq!(
+            restored_into(&saved, monitor(5)),
+            monitor(5).save_state().strat_bytes
+        );
     }
 }
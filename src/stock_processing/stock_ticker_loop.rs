@@ -1,11 +1,15 @@
+use crate::backtesting::engine;
+use crate::backtesting::monte_carlo;
+use crate::backtesting::report::BacktestReport;
+use crate::broker::SimulatedBroker;
+use crate::config::EngineConfig;
 use crate::StockMonitor;
 use apca::data::v2::stream::{Bar, Data};
 use chrono::{Datelike, Utc};
 use crossbeam_channel::Receiver;
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock, RwLockWriteGuard};
 use std::thread::sleep;
-use std::thread::spawn;
 use std::time::Duration;
 use threadpool::ThreadPool;
 
@@ -18,11 +22,13 @@ pub fn start_loop(
     allocated_currency: Arc<RwLock<f64>>,
     bar_data: Receiver<Data>,
     db: Arc<Db>,
-    threadpool: ThreadPool,
+    simulated_broker: Arc<SimulatedBroker>,
+    engine_config: EngineConfig,
 ) {
     if backtesting {
-        backtest_loop(stocks, allocated_currency);
+        backtest_loop(stocks, allocated_currency, simulated_broker, engine_config);
     } else {
+        let threadpool = ThreadPool::new(engine_config.threads);
         start_loop_normal(stocks, allocated_currency, bar_data, db, threadpool);
     }
 }
@@ -56,7 +62,7 @@ fn start_loop_normal(
 
         let mut created = 0;
         //try and get the newest stock data from the alpaca market data processor
-        for data in bar_data.try_recv() {
+        while let Ok(data) = bar_data.try_recv() {
             if let Data::Bar(bar) = data {
                 //Wait about a second per 4 stock monitors, dont want to cause rate limits
                 if created >= 4 {
@@ -66,28 +72,24 @@ fn start_loop_normal(
 
                 //Clone all the arcs to they can be explicitly moved with no fuss
                 let stock: Arc<RwLock<StockMonitor>> = stocks.get(&bar.symbol).unwrap().clone();
-                let assets: Arc<RwLock<f64>> = allocated_currency.clone();
                 let db: Arc<Db> = state_db.clone();
 
                 threadpool.execute(move || {
                     //Explicit move
                     let stock: Arc<RwLock<StockMonitor>> = stock;
-                    let assets: Arc<RwLock<f64>> = assets;
                     let db: Arc<Db> = db;
                     let bar_data: Bar = bar;
 
                     //get write access to stock monitor, should NEVER error because there shouldn't be any panics in this part of the code
                     match stock.write() {
                         Ok(mut stock_wrt) => {
-                            match stock_wrt.run(assets, Some(bar_data)) {
+                            match stock_wrt.run(bar_data) {
                                 Ok(_) => {
                                     info!("Saving stock state for symbol: {}", &stock_wrt.symbol);
                                     //Save the state of the stock to the local stock state DB
                                     let state = stock_wrt.save_state();
-                                    let _ = db.insert(
-                                        stock_wrt.symbol.as_bytes(),
-                                        bincode::serialize(&state).unwrap(),
-                                    );
+                                    let _ =
+                                        db.insert(stock_wrt.symbol.as_bytes(), state.to_bytes());
                                 }
                                 Err(e) => {
                                     error!("[{}] Error: {:#?}", stock_wrt.symbol, e);
@@ -113,38 +115,103 @@ fn start_loop_normal(
 fn backtest_loop(
     stocks: HashMap<String, Arc<RwLock<StockMonitor>>>,
     allocated_currency: Arc<RwLock<f64>>,
+    broker: Arc<SimulatedBroker>,
+    engine_config: EngineConfig,
 ) {
-    let allocated_currency: Arc<RwLock<f64>> = allocated_currency.clone();
-    let last_money_value: f64 = *allocated_currency.clone().read().unwrap();
+    let last_money_value: f64 = *allocated_currency.read().unwrap();
     info!("Processing stocks...");
 
     //Runs all the stock monitors in backtest mode, data will be grabbed from a directory called "backtest_data", with the corresponding symbol being pulled from disk
     //IE: AAPL.csv
-    for stock in stocks {
-        let stock = stock.clone();
-        let assets = allocated_currency.clone();
-        spawn(move || {
-            let stock = stock.1.clone();
-            let assets = assets;
-
-            match stock.write() {
-                Ok(mut stock_wrt) => match stock_wrt.run(assets, None) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        error!("[{}] Error: {:#?}", stock_wrt.symbol, e);
-                    }
-                },
-                Err(e) => {
-                    error!("RWLOCK error: {:#?}", e);
-                }
-            };
-        });
+    //Every monitor stays locked for the whole run, the bars of all symbols are replayed in time order on this thread
+    let mut locked: Vec<RwLockWriteGuard<StockMonitor>> = stocks
+        .values()
+        .map(|stock| stock.write().unwrap())
+        .collect();
+    let mut monitors: BTreeMap<String, &mut StockMonitor> = locked
+        .iter_mut()
+        .map(|stock| (stock.symbol.clone(), &mut **stock))
+        .collect();
+
+    let symbols: Vec<String> = monitors.keys().cloned().collect();
+    let result = match engine::load_portfolio_bars(&symbols, engine_config.backtest_utc_offset)
+        .and_then(|bars| engine::run_portfolio(&mut monitors, &broker, &bars))
+    {
+        Ok(result) => result,
+        Err(e) => {
+            error!("Backtest failed: {:#?}", e);
+            return;
+        }
+    };
+    info!("Done!");
+
+    for symbol in monitors.keys() {
+        let short_buys: Vec<&engine::CashContention> = result
+            .contentions
+            .iter()
+            .filter(|contention| &contention.symbol == symbol)
+            .collect();
+        if let Some(first) = short_buys.first() {
+            let mut held_by: Vec<&String> = short_buys
+                .iter()
+                .flat_map(|contention| contention.held_by.iter())
+                .collect();
+            held_by.sort();
+            held_by.dedup();
+            info!(
+                "[{}] {} buys came up {} shares short on cash while {:?} held positions, first at {}",
+                symbol,
+                short_buys.len(),
+                short_buys
+                    .iter()
+                    .map(|contention| contention.shares_short)
+                    .sum::<f64>(),
+                held_by,
+                first.time
+            );
+        }
+    }
+
+    let report = BacktestReport::new(last_money_value, &broker, result);
+    for stats in report
+        .symbols
+        .iter()
+        .map(|symbol| &symbol.stats)
+        .chain([&report.portfolio])
+    {
+        info!(
+            "[{}] realized: {:.2}, unrealized: {:.2}, max drawdown: {:.2}%, trades: {}, wins: {}, losses: {}",
+            &stats.name,
+            stats.realized_pnl,
+            stats.unrealized_pnl,
+            stats.max_drawdown_pct,
+            stats.trades,
+            stats.wins,
+            stats.losses
+        );
+    }
+    if report.borrow_fees > 0.0 {
+        info!("Borrow fees paid on shorts: {:.2}", report.borrow_fees);
     }
-    sleep(Duration::from_secs(2));
-    info!("Done! Looking for errors from threads");
-    let profit = *allocated_currency.clone().read().unwrap() - last_money_value;
     info!("Ending currency: {}", allocated_currency.read().unwrap());
-    info!("Profit made: {}", profit);
+    info!("Ending equity: {}", report.ending_equity);
+    info!("Profit made: {}", report.ending_equity - last_money_value);
+
+    if let Err(e) = report.write(&engine_config.report_dir) {
+        error!("Could not write backtest report: {:#?}", e);
+    }
+
+    if let Some(monte_carlo) = &engine_config.monte_carlo {
+        match monte_carlo::simulate(&report.trades, last_money_value, monte_carlo) {
+            Some(monte_carlo_report) => {
+                monte_carlo_report.log();
+                if let Err(e) = monte_carlo_report.write(&engine_config.report_dir) {
+                    error!("Could not write Monte Carlo report: {:#?}", e);
+                }
+            }
+            None => info!("No closed trades to run Monte Carlo on"),
+        }
+    }
 }
 
 fn time_check() -> bool {
@@ -0,0 +1,95 @@
+use anyhow::{Error, Result};
+use apca::data::v2::stream::Bar;
+use serde::Deserialize;
+use ta::indicators::AverageTrueRange;
+use ta::{Close, High, Low, Next};
+
+///Set as trailing_stop on a stock in the config, works with any strategy
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum TrailingStopConfig {
+    //The stop sits this percent behind the best price since entry
+    Percent { percent: f64 },
+    //The stop sits multiple ATRs (over period bars of the stock's timeframe) behind the best price since entry
+    Atr { period: usize, multiple: f64 },
+}
+
+//What the ATR is fed, the bars' prices as f64
+struct PriceRange {
+    high: f64,
+    low: f64,
+    close: f64,
+}
+
+impl High for PriceRange {
+    fn high(&self) -> f64 {
+        self.high
+    }
+}
+
+impl Low for PriceRange {
+    fn low(&self) -> f64 {
+        self.low
+    }
+}
+
+impl Close for PriceRange {
+    fn close(&self) -> f64 {
+        self.close
+    }
+}
+
+//Works out how far behind the high water mark the stop is
+//The ATR isn't saved to the DB, after a restart an ATR stop waits for period bars before it can trigger again
+pub struct TrailingStop {
+    config: TrailingStopConfig,
+    atr: AverageTrueRange,
+    bars_seen: usize,
+    last_atr: f64,
+}
+
+impl TrailingStop {
+    pub fn new(config: TrailingStopConfig) -> Result<Self> {
+        let period = match config {
+            TrailingStopConfig::Percent { percent } if percent <= 0.0 => {
+                return Err(Error::msg("Bad trailing_stop: percent has to be above 0"));
+            }
+            TrailingStopConfig::Atr { period, multiple } if period == 0 || multiple <= 0.0 => {
+                return Err(Error::msg(
+                    "Bad trailing_stop: period and multiple have to be above 0",
+                ));
+            }
+            TrailingStopConfig::Atr { period, .. } => period,
+            TrailingStopConfig::Percent { .. } => 1,
+        };
+        Ok(Self {
+            config,
+            atr: AverageTrueRange::new(period)
+                .map_err(|_| Error::msg("Bad trailing_stop: period has to be above 0"))?,
+            bars_seen: 0,
+            last_atr: 0.0,
+        })
+    }
+
+    //Takes every finished bar of the stock's timeframe
+    pub fn update(&mut self, bar: &Bar) {
+        if let TrailingStopConfig::Atr { .. } = self.config {
+            self.last_atr = self.atr.next(&PriceRange {
+                high: bar.high_price.to_f64().unwrap_or_default(),
+                low: bar.low_price.to_f64().unwrap_or_default(),
+                close: bar.close_price.to_f64().unwrap_or_default(),
+            });
+            self.bars_seen += 1;
+        }
+    }
+
+    //How far behind the mark the stop is, None while the ATR is still warming up
+    pub fn distance(&self, mark: f64) -> Option<f64> {
+        match self.config {
+            TrailingStopConfig::Percent { percent } => Some(mark * percent / 100.0),
+            TrailingStopConfig::Atr { period, multiple } => {
+                (self.bars_seen >= period).then_some(self.last_atr * multiple)
+            }
+        }
+    }
+}